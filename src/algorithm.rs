@@ -0,0 +1,76 @@
+use std::time::Duration;
+
+mod fixed_window;
+mod gcra;
+mod sliding_window;
+mod token_bucket;
+
+pub(crate) use fixed_window::{FixedWindow, WallClock};
+pub(crate) use gcra::Gcra;
+pub(crate) use sliding_window::SlidingWindow;
+pub(crate) use token_bucket::TokenBucket;
+
+/// State of a key at a given instant, whatever the algorithm
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Status {
+    /// Number of hits that would be allowed right away
+    pub remaining: u32,
+    /// Time until a hit of `cost` units is allowed, zero when it is,
+    /// `Duration::MAX` when the cost exceeds the limit
+    pub retry_after: Duration,
+    /// Time until all hits are allowed again
+    pub reset_after: Duration,
+}
+
+/// Algorithm used by a `Ratelimit` to decide whether a hit is allowed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum Algorithm {
+    /// Exact sliding log, stores one timestamp per allowed hit
+    #[default]
+    SlidingLog,
+
+    /// Token bucket refilled at a rate of `hits` per `duration`,
+    /// holding at most `burst` tokens.
+    /// Uses a constant amount of memory per key
+    TokenBucket { burst: u32 },
+
+    /// Generic cell rate algorithm, paces hits evenly at `hits` per `duration`
+    /// while allowing a burst of `hits`.
+    /// Stores a single timestamp per key
+    Gcra,
+
+    /// Sliding window counter, approximates the sliding log by weighting
+    /// the hits of the previous window.
+    /// Stores two counters per key
+    SlidingWindow,
+
+    /// Fixed window counter, windows being aligned on UTC boundaries
+    /// (multiples of `duration` since the UNIX epoch) shifted by `offset` milliseconds.
+    /// Stores a single counter per key
+    FixedWindow { offset: u64 },
+}
+
+impl Algorithm {
+    /// Build an algorithm from its short name as used in key specifications,
+    /// `arg` being the optional numeric parameter following the name
+    ///
+    /// - `log`: sliding log
+    /// - `tb` / `tb<burst>`: token bucket, burst defaulting to `hits`
+    /// - `gcra`: generic cell rate algorithm
+    /// - `sw`: sliding window counter
+    /// - `fw` / `fw<offset>`: fixed window aligned on UTC, offset in seconds
+    pub fn from_spec(name: &str, arg: Option<u32>, hits: u32) -> Option<Algorithm> {
+        match (name, arg) {
+            ("log", None) => Some(Algorithm::SlidingLog),
+            ("tb", burst) => Some(Algorithm::TokenBucket {
+                burst: burst.unwrap_or(hits),
+            }),
+            ("gcra", None) => Some(Algorithm::Gcra),
+            ("sw", None) => Some(Algorithm::SlidingWindow),
+            ("fw", offset) => Some(Algorithm::FixedWindow {
+                offset: u64::from(offset.unwrap_or(0)) * 1000,
+            }),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,184 @@
+use std::time::{Duration, Instant};
+
+use super::Status;
+
+/// Maps monotonic instants to UNIX time, so windows can be aligned
+/// on wall-clock boundaries
+#[derive(Clone, Copy)]
+pub(crate) struct WallClock {
+    reference: Instant,
+    unix: Duration,
+}
+
+impl WallClock {
+    pub fn new(reference: Instant, unix: Duration) -> WallClock {
+        WallClock { reference, unix }
+    }
+
+    /// Number of milliseconds since the UNIX epoch at `now`
+    pub fn unix_millis(&self, now: Instant) -> u64 {
+        let unix = match now.checked_duration_since(self.reference) {
+            Some(elapsed) => self.unix + elapsed,
+            None => self.unix.saturating_sub(self.reference.duration_since(now)),
+        };
+
+        u64::try_from(unix.as_millis()).unwrap_or(u64::MAX)
+    }
+
+    /// End of the window of `duration` milliseconds containing `now`,
+    /// windows starting `offset` milliseconds after a multiple of `duration`
+    /// since the UNIX epoch
+    pub fn window_end(&self, now: Instant, duration: u64, offset: u64) -> Instant {
+        let shift = offset % duration;
+        let elapsed = (self.unix_millis(now) + duration - shift) % duration;
+
+        now + Duration::from_millis(duration - elapsed)
+    }
+}
+
+/// State of a single key for the fixed window algorithm
+///
+/// Hits are counted until the end of the current wall-clock aligned window
+pub(crate) struct FixedWindow {
+    ends: Instant,
+    count: u32,
+}
+
+impl FixedWindow {
+    pub fn new(now: Instant) -> FixedWindow {
+        FixedWindow {
+            ends: now,
+            count: 0,
+        }
+    }
+
+    /// Whether `cost` hits would be allowed at `now`, without recording them
+    pub fn check(&self, now: Instant, hits: u32, cost: u32) -> bool {
+        let count = if now >= self.ends { 0 } else { self.count };
+
+        u64::from(count) + u64::from(cost) <= u64::from(hits)
+    }
+
+    /// Hits the limit `cost` times, allowing at most `hits` per window of `duration` milliseconds
+    pub fn hit(
+        &mut self,
+        now: Instant,
+        clock: &WallClock,
+        hits: u32,
+        duration: u64,
+        offset: u64,
+        cost: u32,
+    ) -> bool {
+        if now >= self.ends {
+            self.ends = clock.window_end(now, duration, offset);
+            self.count = 0;
+        }
+
+        if u64::from(self.count) + u64::from(cost) > u64::from(hits) {
+            return false;
+        }
+
+        self.count += cost;
+        true
+    }
+
+    /// Cancels `cost` hits of the current window
+    pub fn refund(&mut self, now: Instant, cost: u32) {
+        if now < self.ends {
+            self.count = self.count.saturating_sub(cost);
+        }
+    }
+
+    /// Status of the limit at `now`, for hits of `cost` units
+    pub fn status(&self, now: Instant, hits: u32, cost: u32) -> Status {
+        let count = if now >= self.ends { 0 } else { self.count };
+        let remaining = hits.saturating_sub(count);
+        let ends = self.ends.saturating_duration_since(now);
+
+        Status {
+            remaining,
+            retry_after: if cost > hits {
+                Duration::MAX
+            } else if remaining >= cost {
+                Duration::ZERO
+            } else {
+                ends
+            },
+            reset_after: if count > 0 { ends } else { Duration::ZERO },
+        }
+    }
+
+    /// End of the current window, when the counter is reset
+    pub fn ends(&self) -> Instant {
+        self.ends
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::{Clock, ManualClock};
+
+    #[test]
+    fn test_window_end() {
+        let clock = ManualClock::new();
+
+        // 2022-10-01 12:34:56 UTC
+        let wall = WallClock::new(clock.now(), Duration::from_secs(1_664_627_696));
+
+        // Top of the next hour
+        assert_eq!(
+            wall.window_end(clock.now(), 3_600_000, 0),
+            clock.now() + Duration::from_secs(25 * 60 + 4)
+        );
+        // Next day, at 06:00
+        assert_eq!(
+            wall.window_end(clock.now(), 86_400_000, 6 * 3_600_000),
+            clock.now() + Duration::from_secs(17 * 3600 + 25 * 60 + 4)
+        );
+        // Same day, at 18:00
+        assert_eq!(
+            wall.window_end(clock.now(), 86_400_000, 18 * 3_600_000),
+            clock.now() + Duration::from_secs(5 * 3600 + 25 * 60 + 4)
+        );
+    }
+
+    #[test]
+    fn test_fixed_window() {
+        let clock = ManualClock::new();
+
+        // 12:34:56 UTC
+        let wall = WallClock::new(clock.now(), Duration::from_secs(45_296));
+        let mut window = FixedWindow::new(clock.now());
+
+        for _ in 0..3 {
+            assert!(window.hit(clock.now(), &wall, 3, 60_000, 0, 1));
+        }
+        assert!(!window.hit(clock.now(), &wall, 3, 60_000, 0, 1));
+
+        // 12:34:59.999
+        clock.advance(Duration::from_millis(3_999));
+        assert!(!window.hit(clock.now(), &wall, 3, 60_000, 0, 1));
+        assert_eq!(
+            window.status(clock.now(), 3, 1),
+            Status {
+                remaining: 0,
+                retry_after: Duration::from_millis(1),
+                reset_after: Duration::from_millis(1),
+            }
+        );
+
+        // 12:35:00
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(window.ends(), clock.now());
+        assert!(window.check(clock.now(), 3, 3));
+        assert!(!window.check(clock.now(), 3, 4));
+        assert!(window.hit(clock.now(), &wall, 3, 60_000, 0, 1));
+        assert_eq!(window.status(clock.now(), 3, 2).retry_after, Duration::ZERO);
+        assert_eq!(
+            window.status(clock.now(), 3, 3).retry_after,
+            Duration::from_secs(60)
+        );
+        assert_eq!(window.status(clock.now(), 3, 4).retry_after, Duration::MAX);
+    }
+}
@@ -0,0 +1,169 @@
+use std::cmp;
+use std::time::{Duration, Instant};
+
+use super::Status;
+
+/// State of a single key for the generic cell rate algorithm
+///
+/// Only the "theoretical arrival time" of the next hit is stored,
+/// each allowed hit pushing it by `duration / hits`.
+/// A hit is allowed as long as the theoretical arrival time stays within
+/// `duration` of the current time
+pub(crate) struct Gcra {
+    tat: Instant,
+}
+
+impl Gcra {
+    pub fn new(now: Instant) -> Gcra {
+        Gcra { tat: now }
+    }
+
+    /// Theoretical arrival time after `cost` hits at `now`,
+    /// `None` if they are not allowed
+    fn next_tat(&self, now: Instant, hits: u32, duration: u64, cost: u32) -> Option<Instant> {
+        let window = Duration::from_millis(duration);
+        let interval = window / hits;
+
+        // Hits taking longer than the window can never be allowed
+        let needed = interval
+            .checked_mul(cost)
+            .filter(|needed| *needed <= window)?;
+        let tat = cmp::max(self.tat, now).checked_add(needed)?;
+
+        if tat.duration_since(now) > window {
+            None
+        } else {
+            Some(tat)
+        }
+    }
+
+    /// Whether `cost` hits would be allowed at `now`, without recording them
+    pub fn check(&self, now: Instant, hits: u32, duration: u64, cost: u32) -> bool {
+        self.next_tat(now, hits, duration, cost).is_some()
+    }
+
+    /// Hits the limit `cost` times, hits / duration (in milliseconds) being the rate
+    pub fn hit(&mut self, now: Instant, hits: u32, duration: u64, cost: u32) -> bool {
+        match self.next_tat(now, hits, duration, cost) {
+            Some(tat) => {
+                self.tat = tat;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Cancels `cost` hits, moving the theoretical arrival time back, never before `now`
+    pub fn refund(&mut self, now: Instant, hits: u32, duration: u64, cost: u32) {
+        let interval = Duration::from_millis(duration) / hits;
+        let tat = interval
+            .checked_mul(cost)
+            .and_then(|refunded| self.tat.checked_sub(refunded))
+            .unwrap_or(now);
+
+        self.tat = cmp::max(tat, now);
+    }
+
+    /// Status of the limit at `now`, for hits of `cost` units
+    pub fn status(&self, now: Instant, hits: u32, duration: u64, cost: u32) -> Status {
+        let interval = Duration::from_millis(duration) / hits;
+        let window = Duration::from_millis(duration);
+        let backlog = self.tat.saturating_duration_since(now);
+
+        let free = window.saturating_sub(backlog).as_nanos() / cmp::max(1, interval.as_nanos());
+        let remaining = cmp::min(u32::try_from(free).unwrap_or(hits), hits);
+
+        let retry_after = match interval.checked_mul(cost) {
+            Some(needed) if needed <= window => {
+                backlog.saturating_add(needed).saturating_sub(window)
+            }
+            _ => Duration::MAX,
+        };
+
+        Status {
+            remaining,
+            retry_after,
+            reset_after: backlog,
+        }
+    }
+
+    /// Instant after which the state is equivalent to a new one
+    pub fn tat(&self) -> Instant {
+        self.tat
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::{Clock, ManualClock};
+
+    #[test]
+    fn test_pacing() {
+        let clock = ManualClock::new();
+
+        // 10 per second
+        let mut gcra = Gcra::new(clock.now());
+
+        for _ in 0..10 {
+            assert!(gcra.hit(clock.now(), 10, 1_000, 1));
+        }
+        assert!(!gcra.hit(clock.now(), 10, 1_000, 1));
+
+        // One slot is freed every 100ms
+        clock.advance(Duration::from_millis(99));
+        assert!(!gcra.hit(clock.now(), 10, 1_000, 1));
+        clock.advance(Duration::from_millis(1));
+        assert!(gcra.hit(clock.now(), 10, 1_000, 1));
+        assert!(!gcra.hit(clock.now(), 10, 1_000, 1));
+
+        assert_eq!(gcra.tat(), clock.now() + Duration::from_secs(1));
+
+        clock.advance(Duration::from_millis(30));
+        assert_eq!(
+            gcra.status(clock.now(), 10, 1_000, 1),
+            Status {
+                remaining: 0,
+                retry_after: Duration::from_millis(70),
+                reset_after: Duration::from_millis(970),
+            }
+        );
+        clock.advance(Duration::from_millis(270));
+        assert_eq!(gcra.status(clock.now(), 10, 1_000, 1).remaining, 3);
+        assert_eq!(
+            gcra.status(clock.now(), 10, 1_000, 5).retry_after,
+            Duration::from_millis(200)
+        );
+        assert_eq!(
+            gcra.status(clock.now(), 10, 1_000, 11).retry_after,
+            Duration::MAX
+        );
+    }
+
+    #[test]
+    fn test_cost() {
+        let clock = ManualClock::new();
+
+        let mut gcra = Gcra::new(clock.now());
+
+        assert!(gcra.hit(clock.now(), 10, 1_000, 8));
+        assert!(!gcra.hit(clock.now(), 10, 1_000, 3));
+        assert!(gcra.hit(clock.now(), 10, 1_000, 2));
+        assert!(!gcra.hit(clock.now(), 10, 1_000, 1));
+
+        clock.advance(Duration::from_millis(500));
+        assert!(!gcra.hit(clock.now(), 10, 1_000, 6));
+        assert!(gcra.check(clock.now(), 10, 1_000, 5));
+        assert!(gcra.hit(clock.now(), 10, 1_000, 5));
+        assert!(!gcra.check(clock.now(), 10, 1_000, 1));
+
+        // Far beyond the range of instants
+        let long = 86_400_000 * 48_000;
+        let mut gcra = Gcra::new(clock.now());
+        assert!(!gcra.hit(clock.now(), 1, long, 3_000_000_000));
+        assert!(!gcra.hit(clock.now(), 1, long, u32::MAX));
+        assert!(gcra.hit(clock.now(), 1, long, 1));
+        gcra.refund(clock.now(), 1, long, u32::MAX);
+        assert_eq!(gcra.tat(), clock.now());
+    }
+}
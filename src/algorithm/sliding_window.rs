@@ -0,0 +1,203 @@
+use std::time::{Duration, Instant};
+
+use super::Status;
+
+/// State of a single key for the sliding window counter algorithm
+///
+/// Hits are counted in fixed windows of `duration`, starting at the first hit.
+/// The number of hits in the sliding window is approximated by weighting
+/// the previous window by the part of it that still overlaps the sliding window
+pub(crate) struct SlidingWindow {
+    start: Instant,
+    previous: u32,
+    current: u32,
+}
+
+impl SlidingWindow {
+    pub fn new(now: Instant) -> SlidingWindow {
+        SlidingWindow {
+            start: now,
+            previous: 0,
+            current: 0,
+        }
+    }
+
+    /// Windows as seen at `now`: `(start, previous, current)`,
+    /// the current window containing `now`
+    fn windows_at(&self, now: Instant, duration: u64) -> (Instant, u32, u32) {
+        let elapsed = now.saturating_duration_since(self.start).as_millis();
+        let windows = elapsed / u128::from(duration);
+
+        let (previous, current) = match windows {
+            0 => return (self.start, self.previous, self.current),
+            1 => (self.current, 0),
+            _ => (0, 0),
+        };
+
+        let windows = u64::try_from(windows).unwrap_or(u64::MAX);
+        let start = self.start + Duration::from_millis(windows.saturating_mul(duration));
+
+        (start, previous, current)
+    }
+
+    /// Whether `cost` more hits fit in the windows, as returned by `windows_at`
+    fn fits(
+        now: Instant,
+        (start, previous, current): (Instant, u32, u32),
+        hits: u32,
+        duration: u64,
+        cost: u32,
+    ) -> bool {
+        let elapsed = now.duration_since(start).as_millis();
+        let duration = u128::from(duration);
+
+        // previous * (duration - elapsed) / duration + current + cost <= hits
+        let weighted = u128::from(previous) * (duration - elapsed)
+            + (u128::from(current) + u128::from(cost)) * duration;
+
+        weighted <= u128::from(hits) * duration
+    }
+
+    /// Whether `cost` hits would be allowed at `now`, without recording them
+    pub fn check(&self, now: Instant, hits: u32, duration: u64, cost: u32) -> bool {
+        SlidingWindow::fits(now, self.windows_at(now, duration), hits, duration, cost)
+    }
+
+    /// Hits the limit `cost` times, allowing at most `hits` in the sliding `duration` (in milliseconds)
+    pub fn hit(&mut self, now: Instant, hits: u32, duration: u64, cost: u32) -> bool {
+        let windows = self.windows_at(now, duration);
+        (self.start, self.previous, self.current) = windows;
+
+        if !SlidingWindow::fits(now, windows, hits, duration, cost) {
+            return false;
+        }
+
+        self.current += cost;
+        true
+    }
+
+    /// Cancels `cost` hits, from the current window then from the previous one
+    pub fn refund(&mut self, now: Instant, duration: u64, cost: u32) {
+        (self.start, self.previous, self.current) = self.windows_at(now, duration);
+
+        let current = self.current.min(cost);
+        self.current -= current;
+        self.previous = self.previous.saturating_sub(cost - current);
+    }
+
+    /// Status of the limit at `now`, for hits of `cost` units
+    pub fn status(&self, now: Instant, hits: u32, duration: u64, cost: u32) -> Status {
+        let (start, previous, current) = self.windows_at(now, duration);
+        let elapsed = now.duration_since(start).as_millis();
+        let (hits, duration) = (u128::from(hits), u128::from(duration));
+        let (previous, current) = (u128::from(previous), u128::from(current));
+        let cost = u128::from(cost);
+
+        // Largest number of hits fitting in the windows, see `fits`
+        let allowed = (hits * duration).saturating_sub(previous * (duration - elapsed)) / duration;
+        let remaining = allowed.saturating_sub(current);
+
+        let retry_after = if cost > hits {
+            None
+        } else if remaining >= cost {
+            Some(0)
+        } else if current + cost <= hits {
+            // Wait for the previous window weight to decrease enough
+            Some(duration - (hits - current - cost) * duration / previous - elapsed)
+        } else {
+            // Wait for the next window, the current one becoming the previous one
+            Some((duration - elapsed) + duration - (hits - cost) * duration / current)
+        };
+
+        let reset_at = if current > 0 {
+            start + Duration::from_millis(2 * u64::try_from(duration).unwrap())
+        } else if previous > 0 {
+            start + Duration::from_millis(u64::try_from(duration).unwrap())
+        } else {
+            now
+        };
+
+        Status {
+            remaining: u32::try_from(remaining).unwrap(),
+            retry_after: retry_after.map_or(Duration::MAX, |retry_after| {
+                Duration::from_millis(u64::try_from(retry_after).unwrap())
+            }),
+            reset_after: reset_at.duration_since(now),
+        }
+    }
+
+    /// Instant after which both windows are empty
+    pub fn expires_at(&self, duration: u64) -> Instant {
+        self.start + Duration::from_millis(2 * duration)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::{Clock, ManualClock};
+
+    #[test]
+    fn test_weighted() {
+        let clock = ManualClock::new();
+
+        let mut window = SlidingWindow::new(clock.now());
+
+        for _ in 0..10 {
+            assert!(window.hit(clock.now(), 10, 1_000, 1));
+        }
+        assert!(!window.hit(clock.now(), 10, 1_000, 1));
+
+        // 10 hits in the previous window, 75% of it still counts
+        clock.advance(Duration::from_millis(1_250));
+        assert!(window.check(clock.now(), 10, 1_000, 2));
+        assert!(!window.check(clock.now(), 10, 1_000, 3));
+        assert_eq!(
+            window.status(clock.now(), 10, 1_000, 1),
+            Status {
+                remaining: 2,
+                retry_after: Duration::ZERO,
+                reset_after: Duration::from_millis(750),
+            }
+        );
+        for _ in 0..2 {
+            assert!(window.hit(clock.now(), 10, 1_000, 1));
+        }
+        assert!(!window.hit(clock.now(), 10, 1_000, 1));
+        // 7.5 + 2 hits: the previous window weight must decrease by 50ms
+        assert_eq!(
+            window.status(clock.now(), 10, 1_000, 1),
+            Status {
+                remaining: 0,
+                retry_after: Duration::from_millis(50),
+                reset_after: Duration::from_millis(1_750),
+            }
+        );
+        assert_eq!(
+            window.status(clock.now(), 10, 1_000, 2).retry_after,
+            Duration::from_millis(150)
+        );
+        assert_eq!(
+            window.status(clock.now(), 10, 1_000, 11).retry_after,
+            Duration::MAX
+        );
+
+        // 2 hits in the previous window, 75% of it still counts
+        clock.advance(Duration::from_millis(1_000));
+        for _ in 0..8 {
+            assert!(window.hit(clock.now(), 10, 1_000, 1));
+        }
+        assert!(!window.hit(clock.now(), 10, 1_000, 1));
+
+        // Both windows are expired
+        clock.advance(Duration::from_millis(2_000));
+        assert_eq!(
+            window.expires_at(1_000),
+            clock.now() - Duration::from_millis(250)
+        );
+        for _ in 0..10 {
+            assert!(window.hit(clock.now(), 10, 1_000, 1));
+        }
+        assert!(!window.hit(clock.now(), 10, 1_000, 1));
+    }
+}
@@ -0,0 +1,150 @@
+use std::time::{Duration, Instant};
+
+use super::Status;
+
+/// State of a single key for the token bucket algorithm
+///
+/// To keep integer arithmetic, a token is worth `duration` units and the
+/// bucket gains `hits` units every millisecond.
+/// Tokens are refilled lazily, when the bucket is hit
+pub(crate) struct TokenBucket {
+    units: u64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// A new, full, bucket
+    pub fn new(duration: u64, burst: u32, now: Instant) -> TokenBucket {
+        TokenBucket {
+            units: u64::from(burst) * duration,
+            updated: now,
+        }
+    }
+
+    /// Number of units available at `now`
+    fn available(&self, now: Instant, hits: u32, duration: u64, burst: u32) -> u64 {
+        let elapsed = now.saturating_duration_since(self.updated).as_millis();
+        let elapsed = u64::try_from(elapsed).unwrap_or(u64::MAX);
+        let capacity = u64::from(burst) * duration;
+
+        capacity.min(
+            self.units
+                .saturating_add(elapsed.saturating_mul(u64::from(hits))),
+        )
+    }
+
+    /// Whether `cost` tokens are available at `now`, without consuming them
+    pub fn check(&self, now: Instant, hits: u32, duration: u64, burst: u32, cost: u32) -> bool {
+        self.available(now, hits, duration, burst) >= u64::from(cost).saturating_mul(duration)
+    }
+
+    /// Hits the bucket, consuming `cost` tokens if available
+    /// hits / duration (in milliseconds) is the refill rate
+    pub fn hit(&mut self, now: Instant, hits: u32, duration: u64, burst: u32, cost: u32) -> bool {
+        self.units = self.available(now, hits, duration, burst);
+        self.updated = now;
+
+        let needed = u64::from(cost).saturating_mul(duration);
+        if self.units < needed {
+            return false;
+        }
+
+        self.units -= needed;
+        true
+    }
+
+    /// Gives back `cost` tokens, the bucket holding at most `burst` tokens
+    pub fn refund(&mut self, now: Instant, hits: u32, duration: u64, burst: u32, cost: u32) {
+        let capacity = u64::from(burst) * duration;
+        let units = self
+            .available(now, hits, duration, burst)
+            .saturating_add(u64::from(cost).saturating_mul(duration));
+
+        self.units = capacity.min(units);
+        self.updated = now;
+    }
+
+    /// Status of the bucket at `now`, for hits of `cost` units
+    pub fn status(&self, now: Instant, hits: u32, duration: u64, burst: u32, cost: u32) -> Status {
+        let available = self.available(now, hits, duration, burst);
+        let capacity = u64::from(burst) * duration;
+        let remaining = available / duration;
+
+        // `cost * duration` fits as `burst * duration` does
+        let retry_after = if cost > burst {
+            Duration::MAX
+        } else {
+            let missing = (u64::from(cost) * duration).saturating_sub(available);
+            Duration::from_millis(missing.div_ceil(u64::from(hits)))
+        };
+
+        Status {
+            remaining: u32::try_from(remaining).unwrap_or(burst),
+            retry_after,
+            reset_after: Duration::from_millis((capacity - available).div_ceil(u64::from(hits))),
+        }
+    }
+
+    /// Instant at which the bucket will be full again
+    pub fn full_at(&self, hits: u32, duration: u64, burst: u32) -> Instant {
+        let capacity = u64::from(burst) * duration;
+        let missing = capacity.saturating_sub(self.units);
+
+        self.updated + Duration::from_millis(missing.div_ceil(u64::from(hits)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::{Clock, ManualClock};
+
+    #[test]
+    fn test_refill() {
+        let clock = ManualClock::new();
+
+        // 10 tokens per second, 5 at most
+        let mut bucket = TokenBucket::new(1_000, 5, clock.now());
+
+        for _ in 0..5 {
+            assert!(bucket.hit(clock.now(), 10, 1_000, 5, 1));
+        }
+        assert!(!bucket.hit(clock.now(), 10, 1_000, 5, 1));
+
+        clock.advance(Duration::from_millis(99));
+        assert!(!bucket.hit(clock.now(), 10, 1_000, 5, 1));
+
+        clock.advance(Duration::from_millis(1));
+        assert!(bucket.hit(clock.now(), 10, 1_000, 5, 1));
+        assert!(!bucket.hit(clock.now(), 10, 1_000, 5, 1));
+
+        // Never more than the burst
+        clock.advance(Duration::from_secs(60));
+        for _ in 0..5 {
+            assert!(bucket.hit(clock.now(), 10, 1_000, 5, 1));
+        }
+        assert!(!bucket.hit(clock.now(), 10, 1_000, 5, 1));
+        assert!(bucket.check(clock.now(), 10, 1_000, 5, 0));
+        assert!(!bucket.check(clock.now(), 10, 1_000, 5, 1));
+        assert_eq!(
+            bucket.status(clock.now(), 10, 1_000, 5, 1),
+            Status {
+                remaining: 0,
+                retry_after: Duration::from_millis(100),
+                reset_after: Duration::from_millis(500),
+            }
+        );
+        assert_eq!(
+            bucket.status(clock.now(), 10, 1_000, 5, 3).retry_after,
+            Duration::from_millis(300)
+        );
+        assert_eq!(
+            bucket.status(clock.now(), 10, 1_000, 5, 6).retry_after,
+            Duration::MAX
+        );
+        assert_eq!(
+            bucket.full_at(10, 1_000, 5),
+            clock.now() + Duration::from_millis(500)
+        );
+    }
+}
@@ -1,55 +1,88 @@
 use std::process::exit;
 use std::{net::SocketAddr, time::Duration};
 
-use ratelimit_rs::StreamHandler;
+use ratelimit_rs::{Stats, StreamHandler};
 
 use async_std::io;
 use async_std::net::TcpListener;
 use async_std::sync::Arc;
 use async_std::task;
 
-use futures::lock::Mutex;
 use futures::stream::StreamExt;
 
-use ratelimit_rs::{Configuration, Ratelimit, RatelimitCollection};
+use ratelimit_rs::{Bounds, ConcurrentCollection, ConcurrentRatelimit, Configuration, Rule, Rules};
+
+/// Maximum number of keys looked at by a cleanup at once, per shard
+const CLEANUP_BATCH: usize = 1_000;
 
 async fn cleanup_timer(
     duration: Duration,
-    rl_arc: Arc<Mutex<Ratelimit>>,
-    meta_arc: Arc<Mutex<RatelimitCollection>>,
+    rl_arc: Arc<ConcurrentRatelimit>,
+    meta_arc: Arc<ConcurrentCollection>,
+    rules_arc: Arc<Rules>,
 ) {
-    //let dur = Duration::from_millis(CLEANUP_INTERVAL);
-
     loop {
         task::sleep(duration).await;
 
-        // let start = Instant::now();
-        let _c = {
-            let mut ratelimit = rl_arc.lock().await;
-            ratelimit.cleanup()
-        } + {
-            let mut meta = meta_arc.lock().await;
-            meta.cleanup()
-        };
-        // Warning: lock + .await means the elapsed time may not be correct
-        // let end = Instant::now();
-        // println!("cleanup time: {:?} ({:?} removed)", (end - start), c);
+        // Expired keys are removed in batches, letting other tasks run in between
+        loop {
+            let _c = rl_arc.cleanup_batch(CLEANUP_BATCH)
+                + meta_arc.cleanup_batch(CLEANUP_BATCH)
+                + rules_arc.cleanup_batch(CLEANUP_BATCH);
+            if !rl_arc.has_expired() && !meta_arc.has_expired() && !rules_arc.has_expired() {
+                break;
+            }
+            task::yield_now().await;
+        }
     }
 }
 
 fn main() -> io::Result<()> {
     let config = Configuration::from_argv()?;
 
-    let ratelimit = Ratelimit::new(
+    let mut ratelimit = ConcurrentRatelimit::new(
         config.ratelimit.hits,
-        (config.ratelimit.seconds * 1000f64) as u32,
+        (config.ratelimit.seconds * 1000f64) as u64,
     )
     .unwrap();
+    let mut collection = ConcurrentCollection::new();
+
+    let bounds = |max_keys| {
+        Bounds::new(max_keys)
+            .with_eviction(config.ratelimit.eviction)
+            .with_fail(config.ratelimit.fail)
+    };
+    if let Some(max_keys) = config.ratelimit.max_keys {
+        ratelimit = ratelimit.with_bounds(bounds(max_keys));
+    }
+    if let Some(max_keys) = config.ratelimit.dynamic_max_keys {
+        collection = collection.with_bounds(bounds(max_keys));
+    }
+    if let Some(max_limits) = config.ratelimit.max_dynamic_limits {
+        collection = collection.with_max_limits(max_limits);
+    }
+    collection = collection.with_allowed(config.ratelimit.allowed_ranges());
+
+    let mut rules = Rules::new();
+    for rule in config.rules.iter() {
+        let mut ratelimits = rule.ratelimits()?;
+        if let Some(max_keys) = config.ratelimit.max_keys {
+            ratelimits = ratelimits
+                .into_iter()
+                .map(|ratelimit| ratelimit.with_bounds(bounds(max_keys)))
+                .collect();
+        }
+        rules = rules.with_rule(Rule::new(&rule.name, rule.pattern()?, ratelimits));
+    }
 
-    let arc = Arc::new(Mutex::new(ratelimit));
-    let arc_collection = Arc::new(Mutex::new(RatelimitCollection::default()));
+    let arc = Arc::new(ratelimit);
+    let arc_collection = Arc::new(collection);
+    let arc_rules = Arc::new(rules);
+    let arc_stats = Arc::new(Stats::new());
 
     let memcache_config = config.handlers.memcache;
+    let retry_after = memcache_config.retry_after;
+    let dynamic_limits = config.ratelimit.dynamic_limits;
 
     let addresses: Vec<SocketAddr> = memcache_config
         .listen
@@ -71,6 +104,7 @@ fn main() -> io::Result<()> {
         cleanup_duration,
         arc.clone(),
         arc_collection.clone(),
+        arc_rules.clone(),
     ));
 
     task::block_on(async {
@@ -79,7 +113,11 @@ fn main() -> io::Result<()> {
 
         while let Some(stream) = incoming.next().await {
             let mut stream = stream?;
-            let handler = StreamHandler::new(&arc, &arc_collection);
+            let mut handler = StreamHandler::new(&arc, &arc_collection)
+                .with_rules(&arc_rules)
+                .with_stats(&arc_stats)
+                .with_retry_after(retry_after)
+                .with_dynamic_limits(dynamic_limits);
             task::spawn(async move { handler.main(&mut stream).await });
         }
         Ok(())
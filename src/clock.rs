@@ -0,0 +1,128 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Source of time of the ratelimits
+///
+/// `now` must be monotonic, `unix` is only used to align fixed windows
+/// on wall-clock boundaries
+pub trait Clock: Clone + Send + Sync {
+    fn now(&self) -> Instant;
+
+    /// Time elapsed since the UNIX epoch
+    fn unix(&self) -> Duration {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+    }
+}
+
+/// The monotonic system clock, the default of ratelimits
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that only moves when told to, for tests and simulations
+///
+/// Clones share the same time
+///
+/// ## Example
+///
+/// ```
+/// use ratelimit_rs::{Algorithm, ManualClock, Ratelimit};
+/// use std::time::Duration;
+///
+/// let clock = ManualClock::new();
+/// let mut rl = Ratelimit::with_clock(1, 1_000, Algorithm::SlidingLog, clock.clone()).unwrap();
+///
+/// assert!(rl.hit("foo"));
+/// assert!(!rl.hit("foo"));
+///
+/// clock.advance(Duration::from_secs(1));
+/// assert!(rl.hit("foo"));
+/// ```
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    origin: Instant,
+    unix: Duration,
+    /// Nanoseconds elapsed since the origin
+    elapsed: Arc<AtomicU64>,
+}
+
+impl ManualClock {
+    /// A clock starting at the current system time
+    pub fn new() -> ManualClock {
+        ManualClock {
+            origin: Instant::now(),
+            unix: SystemClock.unix(),
+            elapsed: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Sets the UNIX time of the origin of the clock
+    pub fn with_unix(mut self, unix: Duration) -> ManualClock {
+        self.unix = unix;
+        self
+    }
+
+    /// Time elapsed since the origin of the clock
+    pub fn elapsed(&self) -> Duration {
+        Duration::from_nanos(self.elapsed.load(Ordering::SeqCst))
+    }
+
+    /// Moves the clock forward
+    pub fn advance(&self, duration: Duration) {
+        self.elapsed.fetch_add(nanos(duration), Ordering::SeqCst);
+    }
+
+    /// Sets the time elapsed since the origin of the clock,
+    /// the clock must not be moved backward while in use
+    pub fn set_elapsed(&self, elapsed: Duration) {
+        self.elapsed.store(nanos(elapsed), Ordering::SeqCst);
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> ManualClock {
+        ManualClock::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.origin + self.elapsed()
+    }
+
+    fn unix(&self) -> Duration {
+        self.unix + self.elapsed()
+    }
+}
+
+fn nanos(duration: Duration) -> u64 {
+    u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_manual_clock() {
+        let clock = ManualClock::new().with_unix(Duration::from_secs(1_664_626_800));
+        let start = clock.now();
+        let other = clock.clone();
+
+        other.advance(Duration::from_millis(1_500));
+        assert_eq!(clock.now(), start + Duration::from_millis(1_500));
+        assert_eq!(clock.unix(), Duration::from_millis(1_664_626_801_500));
+
+        clock.set_elapsed(Duration::from_secs(10));
+        assert_eq!(other.elapsed(), Duration::from_secs(10));
+        assert_eq!(other.now(), start + Duration::from_secs(10));
+    }
+}
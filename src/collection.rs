@@ -1,62 +1,835 @@
-#[cfg(not(test))]
-use std::time::Instant;
-
-#[cfg(test)]
-use mock_instant::Instant;
-
 use rayon::prelude::*;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex, MutexGuard};
 
+use crate::algorithm::Algorithm;
+use crate::clock::{Clock, SystemClock};
+use crate::eviction::{Bounds, FailMode, Rank};
 use crate::ratelimit::{Ratelimit, RatelimitInvalidError};
 
+/// Specification of a limit: `(hits, duration, algorithm)`
+pub type Limit = (u32, u64, Algorithm);
+
+/// A key along with the specification of its limit: `(hits, duration, algorithm, keyname)`
+pub type SpecifiedKey<'a> = (u32, u64, Algorithm, &'a str);
+
+/// A hit recorded by a transaction, to undo if the transaction fails:
+/// `(key, cost, added)`, `added` telling whether the key was added by the hit
+pub(crate) type Recorded<'a> = (SpecifiedKey<'a>, u32, bool);
+
+/// Number of collections holding each limit, shared by the shards of a `ConcurrentCollection`
+/// so that the maximum number of distinct limits is global
+#[derive(Default)]
+pub(crate) struct SharedLimits(Mutex<HashMap<Limit, usize>>);
+
+impl SharedLimits {
+    fn lock(&self) -> MutexGuard<'_, HashMap<Limit, usize>> {
+        self.0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Number of distinct limits
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+}
+
+/// Range of limits a collection accepts, `duration` in milliseconds
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LimitRange {
+    pub hits: RangeInclusive<u32>,
+    pub duration: RangeInclusive<u64>,
+}
+
+impl LimitRange {
+    pub fn new(hits: RangeInclusive<u32>, duration: RangeInclusive<u64>) -> LimitRange {
+        LimitRange { hits, duration }
+    }
+
+    pub fn contains(&self, hits: u32, duration: u64) -> bool {
+        self.hits.contains(&hits) && self.duration.contains(&duration)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum CollectionError {
+    /// The specification of the limit is invalid
+    Invalid(RatelimitInvalidError),
+    /// The limit is not in the allowed ranges
+    NotAllowed { hits: u32, duration: u64 },
+    /// The maximum number of distinct limits is reached
+    TooManyLimits { max: usize },
+    /// A hit can never be allowed, its cost exceeding the limit
+    CostExceedsLimit { cost: u32, limit: u32 },
+}
+
+impl std::error::Error for CollectionError {}
+
+impl std::fmt::Display for CollectionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CollectionError::Invalid(error) => error.fmt(f),
+            CollectionError::NotAllowed { hits, duration } => write!(
+                f,
+                "Limit of {} hits per {}ms is not allowed",
+                hits, duration
+            ),
+            CollectionError::TooManyLimits { max } => {
+                write!(f, "Too many distinct limits, the maximum is {}", max)
+            }
+            CollectionError::CostExceedsLimit { cost, limit } => {
+                write!(f, "Cost of {} exceeds the limit of {} hits", cost, limit)
+            }
+        }
+    }
+}
+
+impl From<RatelimitInvalidError> for CollectionError {
+    fn from(error: RatelimitInvalidError) -> CollectionError {
+        CollectionError::Invalid(error)
+    }
+}
+
 #[derive(Default)]
-pub struct RatelimitCollection {
-    entries: HashMap<(u32, u32), Ratelimit>,
+pub struct RatelimitCollection<C: Clock = SystemClock> {
+    clock: C,
+    entries: HashMap<Limit, Ratelimit<C>>,
+    bounds: Option<Bounds>,
+    evictions: u64,
+    max_limits: Option<usize>,
+    /// Any limit is allowed when empty
+    allowed: Vec<LimitRange>,
+    /// Counts the limits of other collections too, for `max_limits`
+    shared: Option<Arc<SharedLimits>>,
+    /// Number of keys of all the limits, as last synced
+    len: usize,
+    /// Number of keys and rank of the next key to be evicted of each limit, as last synced
+    seen: HashMap<Limit, (usize, Option<Rank>)>,
+    /// Next key to be evicted of each limit, the first one being evicted first
+    victims: BTreeSet<(Rank, Limit)>,
+    /// Limits handed out since the last sync, which may have changed
+    touched: Vec<Limit>,
 }
 
 impl RatelimitCollection {
+    pub fn new() -> RatelimitCollection {
+        RatelimitCollection::default()
+    }
+}
+
+impl<C: Clock> RatelimitCollection<C> {
+    /// A collection whose ratelimits all use the given source of time
+    pub fn with_clock(clock: C) -> RatelimitCollection<C> {
+        RatelimitCollection {
+            clock,
+            entries: HashMap::new(),
+            bounds: None,
+            evictions: 0,
+            max_limits: None,
+            allowed: vec![],
+            shared: None,
+            len: 0,
+            seen: HashMap::new(),
+            victims: BTreeSet::new(),
+            touched: vec![],
+        }
+    }
+
+    /// Bounds the number of distinct limits, limits without keys are removed
+    /// to make room for new ones
+    pub fn with_max_limits(mut self, max_limits: usize) -> RatelimitCollection<C> {
+        self.set_max_limits(max_limits);
+        self
+    }
+
+    pub(crate) fn set_max_limits(&mut self, max_limits: usize) {
+        self.max_limits = Some(max_limits);
+    }
+
+    /// Only accepts limits within one of the ranges
+    pub fn with_allowed(mut self, allowed: Vec<LimitRange>) -> RatelimitCollection<C> {
+        self.set_allowed(allowed);
+        self
+    }
+
+    pub(crate) fn set_allowed(&mut self, allowed: Vec<LimitRange>) {
+        self.allowed = allowed;
+    }
+
+    /// Counts the distinct limits along with other collections, before any limit is created
+    pub(crate) fn set_shared(&mut self, shared: Arc<SharedLimits>) {
+        self.shared = Some(shared);
+    }
+
+    /// Bounds the number of keys of all the limits together, evicting keys of any limit
+    /// on new ones when full. The key added last may exceed the bound until the next operation
+    pub fn with_bounds(mut self, bounds: Bounds) -> RatelimitCollection<C> {
+        self.set_bounds(bounds);
+        self
+    }
+
+    pub(crate) fn set_bounds(&mut self, bounds: Bounds) {
+        self.bounds = Some(bounds);
+        for rl in self.entries.values_mut() {
+            rl.track(bounds.eviction);
+        }
+        self.sync_all();
+    }
+
     pub fn get_instance(
         &mut self,
         hits: u32,
-        duration: u32,
-    ) -> Result<&mut Ratelimit, RatelimitInvalidError> {
-        #[allow(clippy::map_entry)]
-        if !self.entries.contains_key(&(hits, duration)) {
-            let rl = Ratelimit::new(hits, duration)?;
-            self.entries.insert((hits, duration), rl);
+        duration: u64,
+    ) -> Result<&mut Ratelimit<C>, CollectionError> {
+        self.get_instance_with(hits, duration, Algorithm::SlidingLog)
+    }
+
+    pub fn get_instance_with(
+        &mut self,
+        hits: u32,
+        duration: u64,
+        algorithm: Algorithm,
+    ) -> Result<&mut Ratelimit<C>, CollectionError> {
+        let key = (hits, duration, algorithm);
+        self.provide(&[key])?;
+
+        let refuse_new = !self.make_room();
+        self.touch(key);
+        let rl = self.entries.get_mut(&key).unwrap();
+        rl.refuse_new = refuse_new;
+
+        Ok(rl)
+    }
+
+    /// The ratelimits of several limits at once, in no particular order, for stacked limits
+    pub fn get_instances(
+        &mut self,
+        limits: &[Limit],
+    ) -> Result<Vec<&mut Ratelimit<C>>, CollectionError> {
+        self.provide(limits)?;
+
+        let refuse_new = !self.make_room();
+        for limit in limits {
+            self.touch(*limit);
+        }
+
+        Ok(self
+            .entries
+            .iter_mut()
+            .filter(|(limit, _)| limits.contains(limit))
+            .map(|(_, rl)| {
+                rl.refuse_new = refuse_new;
+                rl
+            })
+            .collect())
+    }
+
+    /// Creates the missing limits among `limits`, either all of them or none.
+    /// Limits without keys are removed to make room for them, unless requested
+    fn provide(&mut self, limits: &[Limit]) -> Result<(), CollectionError> {
+        let mut missing: Vec<Limit> = vec![];
+        for &(hits, duration, algorithm) in limits {
+            let limit = (hits, duration, algorithm);
+            if self.entries.contains_key(&limit) || missing.contains(&limit) {
+                continue;
+            }
+            if !self.allowed.is_empty() && !self.allowed.iter().any(|r| r.contains(hits, duration))
+            {
+                return Err(CollectionError::NotAllowed { hits, duration });
+            }
+            missing.push(limit);
+        }
+
+        if missing.is_empty() {
+            return Ok(());
+        }
+
+        // Held until the limits are created, so other collections cannot take their room
+        let shared = self.shared.clone();
+        let mut held = shared.as_deref().map(SharedLimits::lock);
+
+        if let Some(max) = self.max_limits {
+            if self.distinct(&missing, held.as_deref()) > max {
+                self.remove_empty(limits, held.as_deref_mut());
+            }
+            if self.distinct(&missing, held.as_deref()) > max {
+                return Err(CollectionError::TooManyLimits { max });
+            }
+        }
+
+        for (hits, duration, algorithm) in missing {
+            let mut rl = Ratelimit::with_clock(hits, duration, algorithm, self.clock.clone())?;
+            if let Some(bounds) = self.bounds {
+                rl.track(bounds.eviction);
+            }
+            self.entries.insert((hits, duration, algorithm), rl);
+            self.sync((hits, duration, algorithm));
+            if let Some(held) = held.as_mut() {
+                *held.entry((hits, duration, algorithm)).or_default() += 1;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Number of distinct limits once `missing` are created, `held` being the locked shared limits
+    fn distinct(&self, missing: &[Limit], held: Option<&HashMap<Limit, usize>>) -> usize {
+        match held {
+            Some(held) => held.len() + missing.iter().filter(|l| !held.contains_key(l)).count(),
+            None => self.entries.len() + missing.len(),
+        }
+    }
+
+    /// Removes the limits without keys but `keep`, `held` being the locked shared limits
+    fn remove_empty(&mut self, keep: &[Limit], mut held: Option<&mut HashMap<Limit, usize>>) {
+        let empty: Vec<Limit> = self
+            .entries
+            .iter()
+            .filter(|(limit, rl)| rl.is_empty() && !keep.contains(limit))
+            .map(|(limit, _)| *limit)
+            .collect();
+
+        for limit in empty {
+            self.entries.remove(&limit);
+            if let Some(held) = held.as_mut() {
+                if let Some(count) = held.get_mut(&limit) {
+                    *count -= 1;
+                    if *count == 0 {
+                        held.remove(&limit);
+                    }
+                }
+            }
+        }
+        self.sync_all();
+    }
+
+    /// Same as `remove_empty`, locking the shared limits
+    fn remove_all_empty(&mut self) {
+        let shared = self.shared.clone();
+        let mut held = shared.as_deref().map(SharedLimits::lock);
+        self.remove_empty(&[], held.as_deref_mut());
+    }
+
+    /// Evicts the keys added beyond the bounds by the previous operations,
+    /// returns whether a new key can be added, if needed by evicting another one
+    /// at the next call
+    fn make_room(&mut self) -> bool {
+        self.sync_touched();
+
+        let bounds = match self.bounds {
+            Some(bounds) => bounds,
+            None => return true,
+        };
+        let now = self.clock.now();
+
+        while self.len > bounds.max_keys {
+            let limit = match self.victim() {
+                Some(limit) => limit,
+                None => break,
+            };
+            self.entries.get_mut(&limit).unwrap().evict();
+            self.sync(limit);
+            self.evictions += 1;
+        }
+
+        if self.len < bounds.max_keys {
+            return true;
+        }
+
+        match (bounds.fail, self.victim()) {
+            (_, None) => false,
+            (FailMode::Open, Some(_)) => true,
+            (FailMode::Closed, Some(limit)) => {
+                self.entries[&limit].victim_expired(now) == Some(true)
+            }
+        }
+    }
+
+    /// The limit holding the next key to be evicted, among all the limits
+    fn victim(&self) -> Option<Limit> {
+        self.victims.first().map(|(_, limit)| *limit)
+    }
+
+    /// Marks a limit handed out, its keys being synced at the next operation
+    fn touch(&mut self, limit: Limit) {
+        if !self.touched.contains(&limit) {
+            self.touched.push(limit);
+        }
+    }
+
+    /// Updates the number of keys and the next key to be evicted of a limit after it changed
+    fn sync(&mut self, limit: Limit) {
+        let (old_len, old_rank) = self.seen.remove(&limit).unwrap_or_default();
+        if let Some(rank) = old_rank {
+            self.victims.remove(&(rank, limit));
+        }
+        self.len -= old_len;
+
+        if let Some(rl) = self.entries.get(&limit) {
+            let (len, rank) = (rl.len(), rl.victim_rank());
+            if let Some(rank) = rank {
+                self.victims.insert((rank, limit));
+            }
+            self.len += len;
+            self.seen.insert(limit, (len, rank));
+        }
+    }
+
+    /// Syncs the limits handed out since the last sync
+    fn sync_touched(&mut self) {
+        for limit in std::mem::take(&mut self.touched) {
+            self.sync(limit);
         }
+    }
+
+    /// Syncs all the limits, after changes to most of them
+    fn sync_all(&mut self) {
+        self.len = 0;
+        self.seen.clear();
+        self.victims.clear();
+        self.touched = self.entries.keys().copied().collect();
+        self.sync_touched();
+    }
+
+    /// Whether `cost` hits would be allowed for every `(hits, duration, algorithm, keyname)`,
+    /// without recording them
+    pub fn check_all(&mut self, keys: &[SpecifiedKey], cost: u32) -> Result<bool, CollectionError> {
+        let now = self.clock.now();
+        self.provide(&limits_of(keys))?;
+
+        for ((hits, duration, algorithm, keyname), cost) in merge(keys, cost) {
+            let rl = self.get_instance_with(hits, duration, algorithm)?;
+            if !rl.check_at(keyname, cost, now) {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Hits every `(hits, duration, algorithm, keyname)` with a weight of `cost`.
+    /// Either all hits are allowed and recorded, or none is
+    pub fn hit_all(&mut self, keys: &[SpecifiedKey], cost: u32) -> Result<bool, CollectionError> {
+        if !self.check_all(keys, cost)? {
+            return Ok(false);
+        }
+
+        Ok(self.record_all(keys, cost)?.is_some())
+    }
+
+    /// Hits every key, returning the hits recorded so they can be undone if another part
+    /// of a transaction fails. A key may still be refused after `check_all`, when keys added
+    /// before it filled the bounds: the hits already recorded are then undone
+    pub(crate) fn record_all<'a>(
+        &mut self,
+        keys: &[SpecifiedKey<'a>],
+        cost: u32,
+    ) -> Result<Option<Vec<Recorded<'a>>>, CollectionError> {
+        let now = self.clock.now();
+        let mut recorded = vec![];
+        self.provide(&limits_of(keys))?;
+
+        for ((hits, duration, algorithm, keyname), cost) in merge(keys, cost) {
+            let hit = self
+                .get_instance_with(hits, duration, algorithm)
+                .map(|rl| (!rl.contains(keyname), rl.hit_at(keyname, cost, now)));
+
+            match hit {
+                Ok((added, true)) => {
+                    recorded.push(((hits, duration, algorithm, keyname), cost, added))
+                }
+                Ok((_, false)) => {
+                    self.undo(&recorded);
+                    return Ok(None);
+                }
+                Err(error) => {
+                    self.undo(&recorded);
+                    return Err(error);
+                }
+            }
+        }
+
+        Ok(Some(recorded))
+    }
+
+    /// Undoes the hits returned by `record_all`
+    pub(crate) fn undo(&mut self, recorded: &[Recorded]) {
+        for ((hits, duration, algorithm, keyname), cost, added) in recorded {
+            if let Some(rl) = self.entries.get_mut(&(*hits, *duration, *algorithm)) {
+                rl.undo_hit(keyname, *cost, *added);
+            }
+            self.touch((*hits, *duration, *algorithm));
+        }
+    }
+
+    /// Clears the history of a key for a given limit, returns whether the key was known
+    pub fn reset(&mut self, hits: u32, duration: u64, algorithm: Algorithm, keyname: &str) -> bool {
+        let limit = (hits, duration, algorithm);
+        let reset = match self.entries.get_mut(&limit) {
+            Some(rl) => rl.reset(keyname),
+            None => false,
+        };
+        self.touch(limit);
+
+        reset
+    }
+
+    /// Clears the history of a key for every limit, returns the number of limits it was known in
+    pub fn reset_all(&mut self, keyname: &str) -> usize {
+        let reset: Vec<Limit> = self
+            .entries
+            .iter_mut()
+            .filter_map(|(limit, rl)| rl.reset(keyname).then_some(*limit))
+            .collect();
+        for limit in &reset {
+            self.touch(*limit);
+        }
+
+        reset.len()
+    }
+
+    /// Number of distinct limits
+    pub fn limits(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Number of keys, of all the limits
+    pub fn len(&self) -> usize {
+        self.touched.iter().fold(self.len, |len, limit| {
+            let seen = self.seen.get(limit).map_or(0, |(len, _)| *len);
+            let current = self.entries.get(limit).map_or(0, |rl| rl.len());
+            len + current - seen
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of keys evicted to make room for new ones
+    pub fn evictions(&self) -> u64 {
+        self.evictions
+    }
+
+    /// Removes expired keys of all the limits, looking at `batch` keys at most,
+    /// see `Ratelimit::cleanup_batch`
+    pub fn cleanup_batch(&mut self, batch: usize) -> usize {
+        self.make_room();
+
+        let now = self.clock.now();
+        let mut budget = batch;
 
-        Ok(self.entries.get_mut(&(hits, duration)).unwrap())
+        let removed = self
+            .entries
+            .values_mut()
+            .map(|rl| rl.expire(now, &mut budget))
+            .sum();
+        self.remove_all_empty();
+
+        removed
+    }
+
+    pub fn has_expired(&self) -> bool {
+        self.entries.values().any(|rl| rl.has_expired())
     }
 
     pub fn cleanup(&mut self) -> usize {
-        let now = Instant::now();
-        self.entries
+        self.make_room();
+
+        let now = self.clock.now();
+        let removed = self
+            .entries
             .par_iter_mut()
             .map(|(_, val)| val.cleanup_at(now))
-            .sum()
+            .sum();
+        // Limits without keys are created again when needed
+        self.remove_all_empty();
+
+        removed
     }
 }
 
+/// The limits of the keys, so they are all provided before any is used
+fn limits_of(keys: &[SpecifiedKey]) -> Vec<Limit> {
+    keys.iter()
+        .map(|(hits, duration, algorithm, _)| (*hits, *duration, *algorithm))
+        .collect()
+}
+
+/// Sums the cost of keys that are present multiple times,
+/// so they are checked against their limit only once
+fn merge<'a>(keys: &[SpecifiedKey<'a>], cost: u32) -> Vec<(SpecifiedKey<'a>, u32)> {
+    let mut merged: Vec<(SpecifiedKey, u32)> = Vec::with_capacity(keys.len());
+
+    for key in keys {
+        match merged.iter_mut().find(|(other, _)| other == key) {
+            Some((_, total)) => *total = total.saturating_add(cost),
+            None => merged.push((*key, cost)),
+        }
+    }
+
+    merged
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
-    use mock_instant::MockClock;
+    use crate::clock::ManualClock;
     use std::time::Duration;
 
     #[test]
     fn test_collection_cleanup() {
-        let root = Duration::from_millis(86_400_000);
+        let clock = ManualClock::new();
 
-        MockClock::set_time(root);
-
-        let mut meta = RatelimitCollection::default();
+        let mut meta = RatelimitCollection::with_clock(clock.clone());
         meta.get_instance(1, 1000).unwrap().hit("foo");
         meta.get_instance(10, 1_000).unwrap().hit("bar");
         meta.get_instance(8, 10_000).unwrap().hit("bar");
 
-        MockClock::advance(Duration::from_secs(6));
+        clock.advance(Duration::from_secs(6));
 
         assert_eq!(meta.cleanup(), 2);
     }
+
+    #[test]
+    fn test_collection_hit_all() {
+        let clock = ManualClock::new();
+
+        let mut meta = RatelimitCollection::with_clock(clock.clone());
+        let keys = [
+            (10, 1_000, Algorithm::SlidingLog, "ip"),
+            (2, 3_600_000, Algorithm::SlidingLog, "user"),
+            (100, 86_400_000, Algorithm::Gcra, "tenant"),
+        ];
+
+        assert!(meta.check_all(&keys, 2).unwrap());
+        assert!(!meta.check_all(&keys, 3).unwrap());
+
+        assert!(meta.hit_all(&keys, 1).unwrap());
+        assert!(meta.hit_all(&keys, 1).unwrap());
+
+        // The user limit refuses, nothing is charged
+        assert!(!meta.hit_all(&keys, 1).unwrap());
+        assert!(meta.get_instance(10, 1_000).unwrap().hit_n("ip", 8));
+        assert!(meta
+            .get_instance_with(100, 86_400_000, Algorithm::Gcra)
+            .unwrap()
+            .hit_n("tenant", 98));
+
+        // The same key twice counts twice
+        let keys = [
+            (3, 1_000, Algorithm::SlidingLog, "foo"),
+            (3, 1_000, Algorithm::SlidingLog, "foo"),
+        ];
+        assert!(!meta.hit_all(&keys, 2).unwrap());
+        assert!(meta.hit_all(&keys, 1).unwrap());
+        assert!(meta.get_instance(3, 1_000).unwrap().hit("foo"));
+
+        assert!(meta
+            .hit_all(&[(0, 1_000, Algorithm::SlidingLog, "foo")], 1)
+            .is_err());
+
+        // Each key has room on its own, not both: the first one is not recorded either
+        let mut meta = RatelimitCollection::with_clock(clock.clone())
+            .with_bounds(Bounds::new(1).with_fail(FailMode::Closed));
+        let keys = [
+            (5, 1_000, Algorithm::SlidingLog, "a"),
+            (5, 1_000, Algorithm::SlidingLog, "b"),
+        ];
+        assert!(!meta.hit_all(&keys, 1).unwrap());
+        assert!(meta.is_empty());
+        assert!(meta.hit_all(&keys[..1], 1).unwrap());
+        assert_eq!(
+            meta.get_instance(5, 1_000)
+                .unwrap()
+                .check_decision("a", 1)
+                .remaining,
+            4
+        );
+    }
+
+    #[test]
+    fn test_collection_reset() {
+        let clock = ManualClock::new();
+
+        let mut meta = RatelimitCollection::with_clock(clock.clone());
+        meta.get_instance(1, 1_000).unwrap().hit("foo");
+        meta.get_instance(1, 60_000).unwrap().hit("foo");
+        meta.get_instance(1, 60_000).unwrap().hit("bar");
+
+        assert!(meta.reset(1, 1_000, Algorithm::SlidingLog, "foo"));
+        assert!(!meta.reset(1, 1_000, Algorithm::SlidingLog, "foo"));
+        assert!(!meta.reset(1, 2_000, Algorithm::SlidingLog, "foo"));
+        assert!(meta.get_instance(1, 1_000).unwrap().hit("foo"));
+
+        assert_eq!(meta.reset_all("foo"), 2);
+        assert_eq!(meta.reset_all("foo"), 0);
+        assert!(!meta.get_instance(1, 60_000).unwrap().hit("bar"));
+    }
+
+    #[test]
+    fn test_collection_algorithms() {
+        let clock = ManualClock::new();
+
+        let mut meta = RatelimitCollection::with_clock(clock.clone());
+        let bucket = Algorithm::TokenBucket { burst: 1 };
+
+        assert!(meta.get_instance(1, 1_000).unwrap().hit("foo"));
+        assert!(meta.get_instance_with(1, 1_000, bucket).unwrap().hit("foo"));
+
+        assert!(!meta.get_instance(1, 1_000).unwrap().hit("foo"));
+        assert_eq!(
+            meta.get_instance_with(1, 1_000, bucket)
+                .unwrap()
+                .algorithm(),
+            bucket
+        );
+    }
+
+    #[test]
+    fn test_collection_bounds() {
+        let clock = ManualClock::new();
+        let mut meta = RatelimitCollection::with_clock(clock.clone()).with_bounds(Bounds::new(3));
+
+        assert!(meta.get_instance(1, 1_000).unwrap().hit("foo"));
+        clock.advance(Duration::from_millis(10));
+        assert!(meta.get_instance(1, 60_000).unwrap().hit("foo"));
+        assert!(meta.get_instance(1, 60_000).unwrap().hit("bar"));
+
+        // The oldest key of all the limits is evicted, at the next operation
+        assert!(meta.get_instance(1, 60_000).unwrap().hit("baz"));
+        assert!(meta.get_instance(1, 1_000).unwrap().is_empty());
+        assert_eq!(meta.len(), 3);
+        assert_eq!(meta.evictions(), 1);
+        assert!(!meta.get_instance(1, 60_000).unwrap().hit("foo"));
+
+        let mut meta = RatelimitCollection::with_clock(clock.clone())
+            .with_bounds(Bounds::new(1).with_fail(FailMode::Closed));
+
+        assert!(meta.get_instance(1, 1_000).unwrap().hit("foo"));
+        assert!(!meta.get_instance(1, 60_000).unwrap().check("foo"));
+        assert!(!meta.get_instance(1, 60_000).unwrap().hit("foo"));
+
+        clock.advance(Duration::from_millis(1_000));
+        assert!(meta.get_instance(1, 60_000).unwrap().hit("foo"));
+        assert_eq!(meta.cleanup(), 0);
+        assert_eq!(meta.len(), 1);
+        assert_eq!(meta.evictions(), 1);
+    }
+
+    #[test]
+    fn test_collection_bounds_count() {
+        let clock = ManualClock::new();
+        let mut meta = RatelimitCollection::with_clock(clock.clone()).with_bounds(Bounds::new(4));
+
+        // The count of keys follows the changes made through the limits handed out
+        for name in ["foo", "bar", "baz"] {
+            assert!(meta.get_instance(2, 1_000).unwrap().hit(name));
+            clock.advance(Duration::from_millis(10));
+        }
+        assert_eq!(meta.len(), 3);
+        assert!(meta.get_instance(2, 1_000).unwrap().reset("bar"));
+        assert_eq!(meta.len(), 2);
+        assert!(meta
+            .hit_all(&[(1, 60_000, Algorithm::SlidingLog, "foo")], 1)
+            .unwrap());
+        assert_eq!(meta.reset_all("foo"), 2);
+        assert_eq!(meta.len(), 1);
+
+        // "baz" is the oldest key left, then "qux"
+        for name in ["qux", "quux", "corge", "grault"] {
+            assert!(meta.get_instance(1, 60_000).unwrap().hit(name));
+            clock.advance(Duration::from_millis(10));
+        }
+        assert!(meta.get_instance(2, 1_000).unwrap().is_empty());
+        assert!(!meta.get_instance(1, 60_000).unwrap().check("qux"));
+        assert_eq!(meta.len(), 4);
+        assert_eq!(meta.evictions(), 1);
+
+        clock.advance(Duration::from_secs(61));
+        assert_eq!(meta.cleanup(), 4);
+        assert!(meta.is_empty());
+    }
+
+    #[test]
+    fn test_collection_limits() {
+        let clock = ManualClock::new();
+        let mut meta = RatelimitCollection::with_clock(clock.clone())
+            .with_max_limits(2)
+            .with_allowed(vec![
+                LimitRange::new(1..=10, 1_000..=60_000),
+                LimitRange::new(100..=100, 3_600_000..=3_600_000),
+            ]);
+
+        assert!(meta.get_instance(1, 1_000).unwrap().hit("foo"));
+        assert!(matches!(
+            meta.get_instance(11, 1_000),
+            Err(CollectionError::NotAllowed { .. })
+        ));
+        assert!(matches!(
+            meta.get_instance(1, 120_000),
+            Err(CollectionError::NotAllowed { .. })
+        ));
+        assert!(matches!(
+            meta.get_instance(0, 1_000),
+            Err(CollectionError::NotAllowed { .. })
+        ));
+
+        assert!(meta.get_instance(100, 3_600_000).unwrap().hit("foo"));
+        assert!(matches!(
+            meta.get_instance(2, 1_000),
+            Err(CollectionError::TooManyLimits { max: 2 })
+        ));
+
+        // Empty limits are removed on cleanup, or to make room for new ones
+        meta.get_instance(10, 1_000).err().unwrap();
+        assert!(meta.reset(100, 3_600_000, Algorithm::SlidingLog, "foo"));
+        assert!(meta.get_instance(2, 1_000).is_ok());
+        assert_eq!(meta.limits(), 2);
+
+        clock.advance(Duration::from_secs(3));
+        assert_eq!(meta.cleanup(), 1);
+        assert_eq!(meta.limits(), 0);
+    }
+
+    #[test]
+    fn test_collection_limits_stacked() {
+        let clock = ManualClock::new();
+        let stacked = [
+            (5, 1_000, Algorithm::SlidingLog),
+            (1, 3_600_000, Algorithm::SlidingLog),
+        ];
+
+        // The limits are all provided or none, a limit created for the call is not removed
+        let mut meta = RatelimitCollection::with_clock(clock.clone()).with_max_limits(1);
+        assert!(matches!(
+            meta.get_instances(&stacked),
+            Err(CollectionError::TooManyLimits { max: 1 })
+        ));
+        assert_eq!(meta.limits(), 0);
+
+        let mut meta = RatelimitCollection::with_clock(clock.clone()).with_max_limits(2);
+        assert!(meta.get_instance(1, 60_000).unwrap().hit("foo"));
+        assert!(matches!(
+            meta.get_instances(&stacked),
+            Err(CollectionError::TooManyLimits { max: 2 })
+        ));
+        assert!(matches!(
+            meta.hit_all(
+                &[
+                    (5, 1_000, Algorithm::SlidingLog, "foo"),
+                    (1, 3_600_000, Algorithm::SlidingLog, "foo"),
+                ],
+                1
+            ),
+            Err(CollectionError::TooManyLimits { max: 2 })
+        ));
+
+        // Empty limits not requested still make room
+        assert!(meta.reset(1, 60_000, Algorithm::SlidingLog, "foo"));
+        assert_eq!(meta.get_instances(&stacked).unwrap().len(), 2);
+        assert_eq!(meta.limits(), 2);
+    }
 }
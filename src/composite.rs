@@ -0,0 +1,292 @@
+use std::ops::Deref;
+use std::time::Duration;
+
+use crate::clock::{Clock, SystemClock};
+use crate::collection::Limit;
+use crate::ratelimit::{Decision, Ratelimit, RatelimitInvalidError};
+
+/// Several limits stacked on the same keys, such as "10 per second and 300 per minute"
+/// Either a hit is allowed by every limit and recorded by all of them, or it is recorded by none
+///
+/// ## Example
+///
+/// ```
+/// use ratelimit_rs::{Algorithm, CompositeRatelimit};
+///
+/// let mut rl = CompositeRatelimit::new(&[
+///     (10, 1_000, Algorithm::SlidingLog),
+///     (300, 60_000, Algorithm::SlidingLog),
+/// ])
+/// .unwrap();
+///
+/// assert!(rl.hit_n("foo", 10));
+/// assert!(!rl.hit("foo"));
+/// ```
+pub struct CompositeRatelimit<C: Clock = SystemClock> {
+    limits: Vec<Ratelimit<C>>,
+}
+
+impl CompositeRatelimit {
+    pub fn new(limits: &[Limit]) -> Result<CompositeRatelimit, RatelimitInvalidError> {
+        CompositeRatelimit::with_clock(limits, SystemClock)
+    }
+}
+
+impl<C: Clock> CompositeRatelimit<C> {
+    pub fn with_clock(
+        limits: &[Limit],
+        clock: C,
+    ) -> Result<CompositeRatelimit<C>, RatelimitInvalidError> {
+        let limits = limits
+            .iter()
+            .map(|(hits, duration, algorithm)| {
+                Ratelimit::with_clock(*hits, *duration, *algorithm, clock.clone())
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(CompositeRatelimit { limits })
+    }
+
+    pub fn limits(&self) -> &[Ratelimit<C>] {
+        &self.limits
+    }
+
+    fn borrow(&self) -> Vec<&Ratelimit<C>> {
+        self.limits.iter().collect()
+    }
+
+    fn borrow_mut(&mut self) -> Vec<&mut Ratelimit<C>> {
+        self.limits.iter_mut().collect()
+    }
+
+    pub fn hit(&mut self, name: &str) -> bool {
+        self.hit_n(name, 1)
+    }
+
+    pub fn hit_n(&mut self, name: &str, cost: u32) -> bool {
+        hit(&mut self.borrow_mut(), name, cost)
+    }
+
+    pub fn check(&self, name: &str) -> bool {
+        self.check_n(name, 1)
+    }
+
+    pub fn check_n(&self, name: &str, cost: u32) -> bool {
+        check(&self.borrow(), name, cost)
+    }
+
+    /// Same as `hit_n`, returning the decision along with the state of the key
+    /// for the most restrictive limit, see `Decision::and`
+    pub fn hit_decision(&mut self, name: &str, cost: u32) -> Decision {
+        hit_decision(&mut self.borrow_mut(), name, cost)
+    }
+
+    pub fn check_decision(&self, name: &str, cost: u32) -> Decision {
+        check_decision(&self.borrow(), name, cost)
+    }
+
+    /// Clears the history of a key for every limit, returns whether the key was known
+    pub fn reset(&mut self, name: &str) -> bool {
+        self.limits
+            .iter_mut()
+            .map(|rl| usize::from(rl.reset(name)))
+            .sum::<usize>()
+            > 0
+    }
+
+    /// Cancels `cost` hits of a key for every limit, returns whether the key was known
+    pub fn refund(&mut self, name: &str, cost: u32) -> bool {
+        refund(&mut self.borrow_mut(), name, cost)
+    }
+
+    /// Limits a key for `duration` for every limit, returns whether the key was known
+    pub fn block(&mut self, name: &str, duration: Duration) -> bool {
+        block(&mut self.borrow_mut(), name, duration)
+    }
+
+    /// Number of keys of the limit holding the most
+    pub fn len(&self) -> usize {
+        self.limits.iter().map(|rl| rl.len()).max().unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Removes the expired keys of every limit
+    pub fn cleanup(&mut self) -> usize {
+        self.limits.iter_mut().map(|rl| rl.cleanup()).sum()
+    }
+
+    pub fn has_expired(&self) -> bool {
+        self.limits.iter().any(|rl| rl.has_expired())
+    }
+}
+
+/// A single limit, as a stack of one
+impl<C: Clock> From<Ratelimit<C>> for CompositeRatelimit<C> {
+    fn from(ratelimit: Ratelimit<C>) -> CompositeRatelimit<C> {
+        CompositeRatelimit {
+            limits: vec![ratelimit],
+        }
+    }
+}
+
+/// Hits every limit, only if all of them allow it
+pub(crate) fn hit<C: Clock>(limits: &mut [&mut Ratelimit<C>], name: &str, cost: u32) -> bool {
+    let now = match limits.first() {
+        Some(rl) => rl.now(),
+        None => return true,
+    };
+
+    if !limits.iter().all(|rl| rl.check_at(name, cost, now)) {
+        return false;
+    }
+    for rl in limits.iter_mut() {
+        rl.hit_at(name, cost, now);
+    }
+
+    true
+}
+
+pub(crate) fn check<C: Clock>(
+    limits: &[impl Deref<Target = Ratelimit<C>>],
+    name: &str,
+    cost: u32,
+) -> bool {
+    match limits.first() {
+        Some(rl) => {
+            let now = rl.now();
+            limits.iter().all(|rl| rl.check_at(name, cost, now))
+        }
+        None => true,
+    }
+}
+
+pub(crate) fn hit_decision<C: Clock>(
+    limits: &mut [&mut Ratelimit<C>],
+    name: &str,
+    cost: u32,
+) -> Decision {
+    let allowed = hit(limits, name, cost);
+    decision(limits, name, allowed, cost)
+}
+
+pub(crate) fn check_decision<C: Clock>(
+    limits: &[impl Deref<Target = Ratelimit<C>>],
+    name: &str,
+    cost: u32,
+) -> Decision {
+    let allowed = check(limits, name, cost);
+    decision(limits, name, allowed, cost)
+}
+
+pub(crate) fn refund<C: Clock>(limits: &mut [&mut Ratelimit<C>], name: &str, cost: u32) -> bool {
+    limits
+        .iter_mut()
+        .map(|rl| usize::from(rl.refund(name, cost)))
+        .sum::<usize>()
+        > 0
+}
+
+pub(crate) fn block<C: Clock>(
+    limits: &mut [&mut Ratelimit<C>],
+    name: &str,
+    duration: Duration,
+) -> bool {
+    limits
+        .iter_mut()
+        .map(|rl| usize::from(rl.block(name, duration)))
+        .sum::<usize>()
+        > 0
+}
+
+fn decision<C: Clock>(
+    limits: &[impl Deref<Target = Ratelimit<C>>],
+    name: &str,
+    allowed: bool,
+    cost: u32,
+) -> Decision {
+    let unlimited = Decision {
+        allowed,
+        limit: u32::MAX,
+        remaining: u32::MAX,
+        retry_after: Duration::ZERO,
+        reset_after: Duration::ZERO,
+    };
+
+    match limits.first() {
+        Some(rl) => {
+            let now = rl.now();
+            limits
+                .iter()
+                .map(|rl| rl.decision(name, allowed, cost, now))
+                .fold(unlimited, Decision::and)
+        }
+        None => unlimited,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::algorithm::Algorithm;
+    use crate::clock::ManualClock;
+
+    #[test]
+    fn test_composite() {
+        let clock = ManualClock::new();
+
+        let mut rl = CompositeRatelimit::with_clock(
+            &[
+                (2, 1_000, Algorithm::SlidingLog),
+                (3, 60_000, Algorithm::SlidingLog),
+            ],
+            clock.clone(),
+        )
+        .unwrap();
+
+        assert!(rl.hit("foo"));
+        assert!(rl.hit("foo"));
+        let decision = rl.hit_decision("foo", 1);
+        assert!(!decision.allowed);
+        assert_eq!(decision.limit, 2);
+        assert_eq!(decision.remaining, 0);
+        assert_eq!(decision.retry_after, Duration::from_secs(1));
+
+        // The burst limit allows it again, not the sustained one
+        clock.advance(Duration::from_secs(1));
+        assert!(rl.check("foo"));
+        assert!(rl.hit("foo"));
+        let decision = rl.check_decision("foo", 1);
+        assert!(!decision.allowed);
+        assert_eq!(decision.retry_after, Duration::from_secs(59));
+        assert_eq!(decision.reset_after, Duration::from_secs(60));
+
+        // Refused by the sustained limit, not recorded by the burst one
+        clock.advance(Duration::from_secs(1));
+        assert!(!rl.hit("foo"));
+        assert!(rl.limits()[0].check_n("foo", 2));
+
+        assert!(rl.refund("foo", 1));
+        assert!(rl.hit("foo"));
+        assert!(!rl.refund("baz", 1));
+
+        assert!(rl.reset("foo"));
+        assert!(!rl.block("foo", Duration::from_secs(5)));
+        assert!(rl.hit("foo"));
+        assert!(rl.block("foo", Duration::from_secs(5)));
+        assert!(!rl.check("foo"));
+        assert_eq!(
+            rl.check_decision("foo", 1).retry_after,
+            Duration::from_secs(5)
+        );
+        clock.advance(Duration::from_secs(5));
+        assert!(rl.hit_n("foo", 2));
+        assert!(!rl.hit("foo"));
+        assert!(!rl.hit_n("bar", 3));
+        assert_eq!(rl.len(), 1);
+
+        assert!(CompositeRatelimit::new(&[(0, 1_000, Algorithm::SlidingLog)]).is_err());
+    }
+}
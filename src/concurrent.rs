@@ -0,0 +1,524 @@
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::iter::Sum;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+use crate::algorithm::Algorithm;
+use crate::clock::{Clock, SystemClock};
+use crate::collection::{
+    CollectionError, LimitRange, RatelimitCollection, SharedLimits, SpecifiedKey,
+};
+use crate::eviction::Bounds;
+use crate::ratelimit::{Decision, Ratelimit, RatelimitInvalidError};
+
+/// Number of shards per available core, to limit contention between keys
+const SHARDS_PER_CORE: usize = 4;
+
+/// Values split in shards by key hash, each one behind its own lock
+struct Shards<T> {
+    shards: Vec<Mutex<T>>,
+    hasher: RandomState,
+}
+
+impl<T> Shards<T> {
+    fn new(init: impl FnMut() -> T) -> Shards<T> {
+        let cores = thread::available_parallelism().map_or(1, usize::from);
+
+        Shards {
+            shards: std::iter::repeat_with(init)
+                .take(cores * SHARDS_PER_CORE)
+                .map(Mutex::new)
+                .collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    fn index(&self, key: &str) -> usize {
+        let hash = self.hasher.hash_one(key);
+        usize::try_from(hash % self.shards.len() as u64).unwrap()
+    }
+
+    fn lock_index(&self, index: usize) -> MutexGuard<'_, T> {
+        // A panic while holding the lock cannot leave a ratelimit in an inconsistent state
+        self.shards[index]
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn lock(&self, key: &str) -> MutexGuard<'_, T> {
+        self.lock_index(self.index(key))
+    }
+
+    /// Locks the shards of all the items, in ascending order so concurrent callers
+    /// cannot deadlock, returning each lock with the items of its shard
+    fn lock_many<'a, K>(
+        &self,
+        items: &'a [K],
+        key: impl Fn(&K) -> &str,
+    ) -> Vec<(MutexGuard<'_, T>, Vec<&'a K>)> {
+        let mut indexed: Vec<(usize, &K)> = items
+            .iter()
+            .map(|item| (self.index(key(item)), item))
+            .collect();
+        indexed.sort_by_key(|(index, _)| *index);
+
+        let mut locked: Vec<(usize, MutexGuard<T>, Vec<&K>)> = vec![];
+        for (index, item) in indexed {
+            match locked.last_mut() {
+                Some((last, _, items)) if *last == index => items.push(item),
+                _ => locked.push((index, self.lock_index(index), vec![item])),
+            }
+        }
+
+        locked
+            .into_iter()
+            .map(|(_, guard, items)| (guard, items))
+            .collect()
+    }
+
+    fn len(&self) -> usize {
+        self.shards.len()
+    }
+
+    fn iter_mut(&mut self) -> impl Iterator<Item = &mut T> {
+        self.shards.iter_mut().map(|shard| {
+            shard
+                .get_mut()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+        })
+    }
+
+    /// Whether `f` is true for any shard, locked one after the other
+    fn any(&self, f: impl Fn(&T) -> bool) -> bool {
+        (0..self.shards.len()).any(|index| f(&self.lock_index(index)))
+    }
+
+    /// Sum of `f` over every shard, locked one after the other
+    fn sum<S: Sum<S>>(&self, f: impl Fn(&mut T) -> S) -> S {
+        (0..self.shards.len())
+            .map(|index| f(&mut self.lock_index(index)))
+            .sum()
+    }
+}
+
+/// A `Ratelimit` usable from multiple threads at once
+///
+/// Keys are split in shards by hash, hits on keys of different shards
+/// do not wait for each other
+pub struct ConcurrentRatelimit<C: Clock = SystemClock> {
+    shards: Shards<Ratelimit<C>>,
+}
+
+impl ConcurrentRatelimit {
+    pub fn new(hits: u32, duration: u64) -> Result<ConcurrentRatelimit, RatelimitInvalidError> {
+        ConcurrentRatelimit::with_algorithm(hits, duration, Algorithm::SlidingLog)
+    }
+
+    pub fn with_algorithm(
+        hits: u32,
+        duration: u64,
+        algorithm: Algorithm,
+    ) -> Result<ConcurrentRatelimit, RatelimitInvalidError> {
+        ConcurrentRatelimit::with_clock(hits, duration, algorithm, SystemClock)
+    }
+}
+
+impl<C: Clock> ConcurrentRatelimit<C> {
+    pub fn with_clock(
+        hits: u32,
+        duration: u64,
+        algorithm: Algorithm,
+        clock: C,
+    ) -> Result<ConcurrentRatelimit<C>, RatelimitInvalidError> {
+        // Validates the specification once, so the shards can be created infallibly
+        Ratelimit::with_clock(hits, duration, algorithm, clock.clone())?;
+
+        Ok(ConcurrentRatelimit {
+            shards: Shards::new(|| {
+                Ratelimit::with_clock(hits, duration, algorithm, clock.clone()).unwrap()
+            }),
+        })
+    }
+
+    /// Bounds the number of keys, split evenly between the shards. The bound is approximate,
+    /// see `per_shard`
+    pub fn with_bounds(mut self, bounds: Bounds) -> ConcurrentRatelimit<C> {
+        let bounds = per_shard(bounds, self.shards.len());
+        for rl in self.shards.iter_mut() {
+            rl.set_bounds(bounds);
+        }
+        self
+    }
+
+    /// Locks the shard holding `name`, to run several operations on the key at once
+    pub fn lock(&self, name: &str) -> MutexGuard<'_, Ratelimit<C>> {
+        self.shards.lock(name)
+    }
+
+    /// Locks the shards holding all the names, see `Shards::lock_many`
+    pub(crate) fn lock_many<'a, K>(
+        &self,
+        items: &'a [K],
+        key: impl Fn(&K) -> &str,
+    ) -> Vec<(MutexGuard<'_, Ratelimit<C>>, Vec<&'a K>)> {
+        self.shards.lock_many(items, key)
+    }
+
+    pub fn algorithm(&self) -> Algorithm {
+        self.shards.lock_index(0).algorithm()
+    }
+
+    pub fn hit(&self, name: &str) -> bool {
+        self.lock(name).hit(name)
+    }
+
+    pub fn hit_n(&self, name: &str, cost: u32) -> bool {
+        self.lock(name).hit_n(name, cost)
+    }
+
+    pub fn check(&self, name: &str) -> bool {
+        self.lock(name).check(name)
+    }
+
+    pub fn check_n(&self, name: &str, cost: u32) -> bool {
+        self.lock(name).check_n(name, cost)
+    }
+
+    pub fn hit_decision(&self, name: &str, cost: u32) -> Decision {
+        self.lock(name).hit_decision(name, cost)
+    }
+
+    pub fn check_decision(&self, name: &str, cost: u32) -> Decision {
+        self.lock(name).check_decision(name, cost)
+    }
+
+    pub fn reset(&self, name: &str) -> bool {
+        self.lock(name).reset(name)
+    }
+
+    pub fn refund(&self, name: &str, cost: u32) -> bool {
+        self.lock(name).refund(name, cost)
+    }
+
+    pub fn block(&self, name: &str, duration: Duration) -> bool {
+        self.lock(name).block(name, duration)
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.sum(|rl| rl.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn evictions(&self) -> u64 {
+        self.shards.sum(|rl| rl.evictions())
+    }
+
+    /// Cleans up the shards one after the other, hits are only blocked
+    /// on the shard being cleaned up
+    pub fn cleanup(&self) -> usize {
+        self.shards.sum(|rl| rl.cleanup())
+    }
+
+    /// Removes expired keys, looking at `batch` keys at most per shard
+    pub fn cleanup_batch(&self, batch: usize) -> usize {
+        self.shards.sum(|rl| rl.cleanup_batch(batch))
+    }
+
+    pub fn has_expired(&self) -> bool {
+        self.shards.any(|rl| rl.has_expired())
+    }
+}
+
+/// A `RatelimitCollection` usable from multiple threads at once, sharded by key name
+pub struct ConcurrentCollection<C: Clock = SystemClock> {
+    shards: Shards<RatelimitCollection<C>>,
+    /// Distinct limits of all the shards
+    limits: Arc<SharedLimits>,
+}
+
+impl ConcurrentCollection {
+    pub fn new() -> ConcurrentCollection {
+        ConcurrentCollection::with_clock(SystemClock)
+    }
+}
+
+impl Default for ConcurrentCollection {
+    fn default() -> ConcurrentCollection {
+        ConcurrentCollection::new()
+    }
+}
+
+impl<C: Clock> ConcurrentCollection<C> {
+    pub fn with_clock(clock: C) -> ConcurrentCollection<C> {
+        let limits = Arc::new(SharedLimits::default());
+        let mut shards = Shards::new(|| RatelimitCollection::with_clock(clock.clone()));
+        for meta in shards.iter_mut() {
+            meta.set_shared(limits.clone());
+        }
+
+        ConcurrentCollection { shards, limits }
+    }
+
+    /// Bounds the number of keys of all the limits, split evenly between the shards.
+    /// The bound is approximate, see `per_shard`
+    pub fn with_bounds(mut self, bounds: Bounds) -> ConcurrentCollection<C> {
+        let bounds = per_shard(bounds, self.shards.len());
+        for meta in self.shards.iter_mut() {
+            meta.set_bounds(bounds);
+        }
+        self
+    }
+
+    /// Bounds the number of distinct limits of all the shards together, limits without keys
+    /// being removed to make room only within the shard of the new key, or on cleanup
+    pub fn with_max_limits(mut self, max_limits: usize) -> ConcurrentCollection<C> {
+        for meta in self.shards.iter_mut() {
+            meta.set_max_limits(max_limits);
+        }
+        self
+    }
+
+    /// Only accepts limits within one of the ranges
+    pub fn with_allowed(mut self, allowed: Vec<LimitRange>) -> ConcurrentCollection<C> {
+        for meta in self.shards.iter_mut() {
+            meta.set_allowed(allowed.clone());
+        }
+        self
+    }
+
+    /// Locks the shard holding `keyname`, for every limit
+    pub fn lock(&self, keyname: &str) -> MutexGuard<'_, RatelimitCollection<C>> {
+        self.shards.lock(keyname)
+    }
+
+    /// Locks the shards holding all the keys, see `Shards::lock_many`
+    pub(crate) fn lock_many<'k>(
+        &self,
+        keys: &[SpecifiedKey<'k>],
+    ) -> Vec<(
+        MutexGuard<'_, RatelimitCollection<C>>,
+        Vec<SpecifiedKey<'k>>,
+    )> {
+        self.shards
+            .lock_many(keys, |(_, _, _, keyname)| keyname)
+            .into_iter()
+            .map(|(guard, keys)| (guard, keys.into_iter().copied().collect()))
+            .collect()
+    }
+
+    /// See `RatelimitCollection::check_all`
+    pub fn check_all(&self, keys: &[SpecifiedKey], cost: u32) -> Result<bool, CollectionError> {
+        for (mut meta, keys) in self.lock_many(keys) {
+            if !meta.check_all(&keys, cost)? {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// See `RatelimitCollection::hit_all`, all the shards involved are locked
+    /// until every key is hit
+    pub fn hit_all(&self, keys: &[SpecifiedKey], cost: u32) -> Result<bool, CollectionError> {
+        let mut locked = self.lock_many(keys);
+
+        for (meta, keys) in locked.iter_mut() {
+            if !meta.check_all(keys, cost)? {
+                return Ok(false);
+            }
+        }
+
+        // Time only moves forward, what was allowed above still is, unless keys added
+        // fill the bounds of a shard: the hits recorded in the previous shards are then undone
+        let mut recorded = vec![];
+        for (meta, keys) in locked.iter_mut() {
+            match meta.record_all(keys, cost) {
+                Ok(Some(hits)) => recorded.push(hits),
+                failed => {
+                    for ((meta, _), hits) in locked.iter_mut().zip(&recorded) {
+                        meta.undo(hits);
+                    }
+                    return failed.map(|_| false);
+                }
+            }
+        }
+
+        Ok(true)
+    }
+
+    pub fn reset(&self, hits: u32, duration: u64, algorithm: Algorithm, keyname: &str) -> bool {
+        self.lock(keyname).reset(hits, duration, algorithm, keyname)
+    }
+
+    pub fn reset_all(&self, keyname: &str) -> usize {
+        self.lock(keyname).reset_all(keyname)
+    }
+
+    /// Number of distinct limits of all the shards, see `with_max_limits`
+    pub fn limits(&self) -> usize {
+        self.limits.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.sum(|meta| meta.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn evictions(&self) -> u64 {
+        self.shards.sum(|meta| meta.evictions())
+    }
+
+    pub fn cleanup(&self) -> usize {
+        self.shards.sum(|meta| meta.cleanup())
+    }
+
+    /// Removes expired keys, looking at `batch` keys at most per shard
+    pub fn cleanup_batch(&self, batch: usize) -> usize {
+        self.shards.sum(|meta| meta.cleanup_batch(batch))
+    }
+
+    pub fn has_expired(&self) -> bool {
+        self.shards.any(|meta| meta.has_expired())
+    }
+}
+
+/// Bounds of a single shard, rounded up so the total is at least the requested bound
+///
+/// Each shard enforces its own share: the total may exceed the bound by up to `shards - 1`
+/// keys, and a full shard evicts, or refuses when failing closed, new keys while other
+/// shards still have room
+fn per_shard(bounds: Bounds, shards: usize) -> Bounds {
+    Bounds {
+        max_keys: bounds.max_keys.div_ceil(shards),
+        ..bounds
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::eviction::FailMode;
+    use std::time::Duration;
+
+    #[test]
+    fn test_concurrent_hits() {
+        let clock = ManualClock::new();
+        let rl =
+            ConcurrentRatelimit::with_clock(1_000, 60_000, Algorithm::SlidingLog, clock.clone())
+                .unwrap();
+
+        // Exactly the limit is allowed, whatever the interleaving
+        let allowed: usize = thread::scope(|scope| {
+            let threads: Vec<_> = (0..8)
+                .map(|_| scope.spawn(|| (0..500).filter(|_| rl.hit("foo")).count()))
+                .collect();
+            threads.into_iter().map(|t| t.join().unwrap()).sum()
+        });
+        assert_eq!(allowed, 1_000);
+
+        for i in 0..100 {
+            assert!(rl.hit(&format!("key{}", i)));
+        }
+        assert_eq!(rl.len(), 101);
+
+        clock.advance(Duration::from_secs(62));
+        assert_eq!(rl.cleanup(), 101);
+        assert!(rl.is_empty());
+    }
+
+    #[test]
+    fn test_concurrent_hit_all() {
+        let clock = ManualClock::new();
+        let meta = ConcurrentCollection::with_clock(clock.clone());
+
+        let keys: Vec<String> = (0..50).map(|i| format!("key{}", i)).collect();
+        let mut specified: Vec<SpecifiedKey> = keys
+            .iter()
+            .map(|key| (2, 1_000, Algorithm::SlidingLog, key.as_str()))
+            .collect();
+
+        assert!(meta.hit_all(&specified, 1).unwrap());
+        assert!(meta.check_all(&specified, 1).unwrap());
+
+        // A single limited key, spread among many shards, refuses them all
+        specified.push((1, 1_000, Algorithm::SlidingLog, "key0"));
+        assert!(meta.hit_all(&specified, 1).unwrap());
+        assert!(!meta.hit_all(&specified, 1).unwrap());
+        assert!(!meta.check_all(&specified[1..], 1).unwrap());
+
+        assert_eq!(meta.reset_all("key0"), 2);
+        assert!(!meta.reset(1, 1_000, Algorithm::SlidingLog, "key0"));
+        clock.advance(Duration::from_secs(3));
+        assert_eq!(meta.cleanup(), 49);
+    }
+
+    #[test]
+    fn test_concurrent_max_limits() {
+        let clock = ManualClock::new();
+        let shards = Shards::new(|| ()).len();
+
+        for max in [1, 2, 2 * shards] {
+            let meta = ConcurrentCollection::with_clock(clock.clone()).with_max_limits(max);
+
+            let created = (0..20_000)
+                .filter(|i| {
+                    let key = format!("key{}", i);
+                    let mut meta = meta.lock(&key);
+                    meta.get_instance(i + 1, 1_000)
+                        .map(|rl| rl.hit(&key))
+                        .is_ok()
+                })
+                .count();
+            assert_eq!(created, max);
+            assert_eq!(meta.limits(), max);
+        }
+
+        // A limit used by keys of several shards counts once
+        let meta = ConcurrentCollection::with_clock(clock.clone()).with_max_limits(2);
+        for i in 0..1_000 {
+            let key = format!("key{}", i);
+            assert!(meta.lock(&key).get_instance(1, 1_000).unwrap().hit(&key));
+        }
+        assert!(meta.lock("foo").get_instance(2, 1_000).unwrap().hit("foo"));
+        assert_eq!(meta.limits(), 2);
+
+        // Limits without keys are released on cleanup
+        assert!(meta.lock("foo").get_instance(3, 1_000).is_err());
+        clock.advance(Duration::from_secs(3));
+        meta.cleanup();
+        assert_eq!(meta.limits(), 0);
+        assert!(meta.lock("foo").get_instance(3, 1_000).is_ok());
+    }
+
+    #[test]
+    fn test_concurrent_hit_all_undo() {
+        let clock = ManualClock::new();
+        let meta = ConcurrentCollection::with_clock(clock.clone())
+            .with_bounds(Bounds::new(1).with_fail(FailMode::Closed));
+
+        // A key alone in a first shard, two keys in a later one, which refuses the second
+        let names: Vec<String> = (0..1_000).map(|i| format!("key{}", i)).collect();
+        let shard = |name: &String| meta.shards.index(name);
+        let first = names.iter().find(|name| shard(name) == 0).unwrap();
+        let mut later = names.iter().filter(|name| shard(name) == 1);
+        let keys: Vec<SpecifiedKey> = [first, later.next().unwrap(), later.next().unwrap()]
+            .iter()
+            .map(|name| (5, 1_000, Algorithm::SlidingLog, name.as_str()))
+            .collect();
+
+        assert!(meta.check_all(&keys, 1).unwrap());
+        assert!(!meta.hit_all(&keys, 1).unwrap());
+        assert!(meta.is_empty());
+        assert!(meta.hit_all(&keys[..2], 1).unwrap());
+        assert_eq!(meta.len(), 2);
+    }
+}
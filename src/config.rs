@@ -1,19 +1,109 @@
-use std::{env, fs, io::Error};
+use std::io::{Error, ErrorKind};
+use std::{cmp, env, fs};
 
+use regex::Regex;
 use serde::Deserialize;
 
+use crate::algorithm::Algorithm;
+use crate::concurrent::ConcurrentRatelimit;
+use crate::rules::KeyPattern;
+
+use crate::collection::{Limit, LimitRange};
+use crate::eviction::{Eviction, FailMode};
+
 #[derive(Deserialize, Debug)]
 pub struct RLConfig {
     pub hits: u32,
     pub seconds: f64,
 
     pub cleanup_interval: u32,
+
+    /// Maximum number of keys of the default limit, enforced per shard,
+    /// see `ConcurrentRatelimit::with_bounds`
+    pub max_keys: Option<usize>,
+    /// Maximum number of keys of all the dynamic limits together, enforced per shard,
+    /// see `ConcurrentCollection::with_bounds`
+    pub dynamic_max_keys: Option<usize>,
+    #[serde(default)]
+    pub eviction: Eviction,
+    #[serde(default)]
+    pub fail: FailMode,
+
+    /// Allow clients to specify their own limits, see `StreamHandler::with_dynamic_limits`
+    #[serde(default = "enabled")]
+    pub dynamic_limits: bool,
+    pub dynamic_min_hits: Option<u32>,
+    pub dynamic_max_hits: Option<u32>,
+    pub dynamic_min_seconds: Option<f64>,
+    pub dynamic_max_seconds: Option<f64>,
+
+    /// Maximum number of distinct dynamic limits, see `ConcurrentCollection::with_max_limits`
+    pub max_dynamic_limits: Option<usize>,
+    /// Dynamic limits allowed, any if empty
+    #[serde(default)]
+    pub allowed_limits: Vec<AllowedLimitConfig>,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+impl RLConfig {
+    /// Ranges of allowed dynamic limits, within the minimum and maximum hits and durations,
+    /// any limit is allowed if empty
+    pub fn allowed_ranges(&self) -> Vec<LimitRange> {
+        let bounded = LimitRange::new(
+            self.dynamic_min_hits.unwrap_or(0)..=self.dynamic_max_hits.unwrap_or(u32::MAX),
+            millis(self.dynamic_min_seconds, 0)..=millis(self.dynamic_max_seconds, u64::MAX),
+        );
+
+        if self.allowed_limits.is_empty() {
+            return vec![bounded];
+        }
+
+        self.allowed_limits
+            .iter()
+            .map(|allowed| {
+                let range = allowed.range();
+                LimitRange::new(
+                    cmp::max(*range.hits.start(), *bounded.hits.start())
+                        ..=cmp::min(*range.hits.end(), *bounded.hits.end()),
+                    cmp::max(*range.duration.start(), *bounded.duration.start())
+                        ..=cmp::min(*range.duration.end(), *bounded.duration.end()),
+                )
+            })
+            .collect()
+    }
+}
+
+fn millis(seconds: Option<f64>, default: u64) -> u64 {
+    seconds.map_or(default, |seconds| (seconds * 1000f64) as u64)
+}
+
+/// Inclusive ranges of hits and seconds of allowed dynamic limits
+#[derive(Deserialize, Debug)]
+pub struct AllowedLimitConfig {
+    pub hits: (u32, u32),
+    pub seconds: (f64, f64),
+}
+
+impl AllowedLimitConfig {
+    pub fn range(&self) -> LimitRange {
+        let (min, max) = self.seconds;
+        LimitRange::new(
+            self.hits.0..=self.hits.1,
+            (min * 1000f64) as u64..=(max * 1000f64) as u64,
+        )
+    }
 }
 
 #[derive(Deserialize, Debug)]
 pub struct MCacheConfig {
     pub enabled: bool,
     pub listen: Vec<String>,
+
+    #[serde(default)]
+    pub retry_after: bool,
 }
 
 #[derive(Deserialize, Debug)]
@@ -21,10 +111,118 @@ pub struct HandlersConfig {
     pub memcache: MCacheConfig,
 }
 
+/// Named limits applied to the keys matching either a regular expression or a prefix
+///
+/// The limits are stacked: the rule's own `hits` and `seconds`, if set, then its `limits`
+#[derive(Deserialize, Debug)]
+pub struct RuleConfig {
+    pub name: String,
+    #[serde(rename = "match")]
+    pub regex: Option<String>,
+    pub prefix: Option<String>,
+
+    pub hits: Option<u32>,
+    pub seconds: Option<f64>,
+    pub algorithm: Option<String>,
+    #[serde(default)]
+    pub limits: Vec<LimitConfig>,
+}
+
+impl RuleConfig {
+    pub fn pattern(&self) -> Result<KeyPattern, Error> {
+        match (&self.regex, &self.prefix) {
+            (Some(regex), None) => Regex::new(regex)
+                .map(KeyPattern::Regex)
+                .map_err(|e| invalid(&self.name, &e.to_string())),
+            (None, Some(prefix)) => Ok(KeyPattern::Prefix(prefix.clone())),
+            _ => Err(invalid(
+                &self.name,
+                "exactly one of match or prefix must be set",
+            )),
+        }
+    }
+
+    pub fn limits(&self) -> Result<Vec<Limit>, Error> {
+        let own = match (self.hits, self.seconds) {
+            (Some(hits), Some(seconds)) => Some(LimitConfig {
+                hits,
+                seconds,
+                algorithm: self.algorithm.clone(),
+            }),
+            (None, None) if self.algorithm.is_none() => None,
+            _ => return Err(invalid(&self.name, "both hits and seconds must be set")),
+        };
+
+        let limits = own
+            .iter()
+            .chain(self.limits.iter())
+            .map(|limit| limit.limit().map_err(|e| invalid(&self.name, &e)))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if limits.is_empty() {
+            return Err(invalid(&self.name, "no limit is set"));
+        }
+        Ok(limits)
+    }
+
+    pub fn ratelimits(&self) -> Result<Vec<ConcurrentRatelimit>, Error> {
+        self.limits()?
+            .into_iter()
+            .map(|(hits, duration, algorithm)| {
+                ConcurrentRatelimit::with_algorithm(hits, duration, algorithm)
+                    .map_err(|e| invalid(&self.name, &e.to_string()))
+            })
+            .collect()
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct LimitConfig {
+    pub hits: u32,
+    pub seconds: f64,
+    /// Algorithm as in keys specifying their limit, such as "tb5" or "gcra"
+    pub algorithm: Option<String>,
+}
+
+impl LimitConfig {
+    pub fn limit(&self) -> Result<Limit, String> {
+        let duration = (self.seconds * 1000f64) as u64;
+        let spec = match &self.algorithm {
+            Some(spec) => spec,
+            None => return Ok((self.hits, duration, Algorithm::SlidingLog)),
+        };
+
+        let unknown = || format!("unknown algorithm {}", spec);
+
+        let split = spec
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(spec.len());
+        let (name, arg) = spec.split_at(split);
+        let arg = match arg {
+            "" => None,
+            arg => Some(arg.parse().map_err(|_| unknown())?),
+        };
+
+        let algorithm = Algorithm::from_spec(name, arg, self.hits).ok_or_else(unknown)?;
+        Ok((self.hits, duration, algorithm))
+    }
+}
+
+fn invalid(rule: &str, reason: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("Invalid rule {}: {}", rule, reason),
+    )
+}
+
 #[derive(Deserialize, Debug)]
 pub struct Configuration {
     pub ratelimit: RLConfig,
     pub handlers: HandlersConfig,
+
+    /// Rules in order of priority
+    #[serde(default)]
+    pub rules: Vec<RuleConfig>,
 }
 
 impl Configuration {
@@ -40,3 +238,95 @@ impl Configuration {
         Ok(res)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_allowed_ranges() {
+        let config: RLConfig = toml::from_str(
+            r#"
+            hits = 5
+            seconds = 10
+            cleanup_interval = 30
+            dynamic_max_hits = 100
+            dynamic_min_seconds = 0.5
+
+            [[allowed_limits]]
+            hits = [10, 1000]
+            seconds = [0, 60]
+            "#,
+        )
+        .unwrap();
+
+        assert!(config.dynamic_limits);
+        assert_eq!(
+            config.allowed_ranges(),
+            vec![LimitRange::new(10..=100, 500..=60_000)]
+        );
+    }
+
+    #[test]
+    fn test_rules() {
+        let config: Configuration = toml::from_str(
+            r#"
+            [ratelimit]
+            hits = 5
+            seconds = 10
+            cleanup_interval = 30
+
+            [handlers.memcache]
+            enabled = true
+            listen = []
+
+            [[rules]]
+            name = "login"
+            match = "^login:"
+            hits = 5
+            seconds = 60
+
+            [[rules]]
+            name = "api"
+            prefix = "api:"
+            limits = [
+                { hits = 10, seconds = 1, algorithm = "tb20" },
+                { hits = 300, seconds = 60 },
+            ]
+
+            [[rules]]
+            name = "both"
+            match = "^foo"
+            prefix = "foo"
+            hits = 1
+            seconds = 1
+            algorithm = "nope"
+
+            [[rules]]
+            name = "none"
+            prefix = "bar"
+            "#,
+        )
+        .unwrap();
+
+        let rules = &config.rules;
+        assert_eq!(rules.len(), 4);
+        assert!(matches!(rules[0].pattern(), Ok(KeyPattern::Regex(_))));
+        assert_eq!(
+            rules[0].limits().unwrap(),
+            vec![(5, 60_000, Algorithm::SlidingLog)]
+        );
+        assert!(matches!(rules[1].pattern(), Ok(KeyPattern::Prefix(prefix)) if prefix == "api:"));
+        assert_eq!(
+            rules[1].limits().unwrap(),
+            vec![
+                (10, 1_000, Algorithm::TokenBucket { burst: 20 }),
+                (300, 60_000, Algorithm::SlidingLog)
+            ]
+        );
+        assert_eq!(rules[1].ratelimits().unwrap().len(), 2);
+        assert!(rules[2].pattern().is_err());
+        assert!(rules[2].limits().is_err());
+        assert!(rules[3].limits().is_err());
+    }
+}
@@ -0,0 +1,144 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Instant;
+
+use serde::Deserialize;
+
+/// Which key to evict when a ratelimit is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Eviction {
+    /// The least recently used key
+    #[default]
+    Lru,
+    /// The least frequently used key, the least recently used one on ties
+    Lfu,
+}
+
+/// What happens to a new key when a ratelimit is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FailMode {
+    /// A key is evicted to make room, its history is lost and its next hits are allowed
+    #[default]
+    Open,
+    /// Only a key whose hits all expired is evicted, otherwise the new key is limited
+    Closed,
+}
+
+/// Bounds of the number of keys tracked by a ratelimit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bounds {
+    pub max_keys: usize,
+    pub eviction: Eviction,
+    pub fail: FailMode,
+}
+
+impl Bounds {
+    pub fn new(max_keys: usize) -> Bounds {
+        Bounds {
+            max_keys,
+            eviction: Eviction::default(),
+            fail: FailMode::default(),
+        }
+    }
+
+    pub fn with_eviction(mut self, eviction: Eviction) -> Bounds {
+        self.eviction = eviction;
+        self
+    }
+
+    pub fn with_fail(mut self, fail: FailMode) -> Bounds {
+        self.fail = fail;
+        self
+    }
+}
+
+/// Position of a key in the eviction order: `(uses, last use, sequence)`
+///
+/// `uses` is always 0 for LRU, instants are comparable between ratelimits
+/// sharing a clock, the sequence number only makes positions unique
+pub(crate) type Rank = (u64, Instant, u64);
+
+/// Keys of a ratelimit sorted from the first to the last to be evicted
+#[derive(Default)]
+pub(crate) struct Order {
+    eviction: Eviction,
+    sequence: u64,
+    ranks: BTreeMap<Rank, Arc<str>>,
+}
+
+impl Order {
+    pub fn new(eviction: Eviction) -> Order {
+        Order {
+            eviction,
+            ..Order::default()
+        }
+    }
+
+    fn rank(&mut self, uses: u64, now: Instant) -> Rank {
+        self.sequence += 1;
+        match self.eviction {
+            Eviction::Lru => (0, now, self.sequence),
+            Eviction::Lfu => (uses, now, self.sequence),
+        }
+    }
+
+    pub fn insert(&mut self, name: &Arc<str>, now: Instant) -> Rank {
+        let rank = self.rank(1, now);
+        self.ranks.insert(rank, Arc::clone(name));
+        rank
+    }
+
+    /// Records a use of the key, returning its new rank
+    pub fn touch(&mut self, rank: Rank, now: Instant) -> Rank {
+        let name = self.ranks.remove(&rank).unwrap();
+        let new_rank = self.rank(rank.0 + 1, now);
+        self.ranks.insert(new_rank, name);
+        new_rank
+    }
+
+    pub fn remove(&mut self, rank: Rank) {
+        self.ranks.remove(&rank);
+    }
+
+    /// The next key to be evicted
+    pub fn victim(&self) -> Option<(Rank, &str)> {
+        self.ranks
+            .iter()
+            .next()
+            .map(|(rank, name)| (*rank, &**name))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_order() {
+        let now = Instant::now();
+        let later = now + Duration::from_secs(1);
+        let (foo_name, bar_name): (Arc<str>, Arc<str>) = (Arc::from("foo"), Arc::from("bar"));
+
+        let mut lru = Order::new(Eviction::Lru);
+        let foo = lru.insert(&foo_name, now);
+        lru.insert(&bar_name, now);
+        lru.touch(foo, later);
+        assert_eq!(lru.victim().map(|(_, name)| name), Some("bar"));
+
+        let mut lfu = Order::new(Eviction::Lfu);
+        let foo = lfu.insert(&foo_name, now);
+        let foo = lfu.touch(foo, now);
+        let bar = lfu.insert(&bar_name, later);
+        assert_eq!(lfu.victim().map(|(_, name)| name), Some("bar"));
+
+        let bar = lfu.touch(bar, later);
+        lfu.touch(foo, now);
+        assert_eq!(lfu.victim().map(|(_, name)| name), Some("bar"));
+
+        lfu.remove(bar);
+        assert_eq!(lfu.victim().map(|(_, name)| name), Some("foo"));
+    }
+}
@@ -0,0 +1,80 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::sync::Arc;
+use std::time::Instant;
+
+/// Keys sorted by expiry, so expired keys can be removed without scanning all the keys
+///
+/// Expiries are only pushed when a key is added, they may be earlier than the actual
+/// expiry of a key that was hit since: such keys are pushed again when popped.
+/// Each push has an id, so pushes of removed keys can be told apart from pushes of
+/// keys added again under the same name
+#[derive(Default)]
+pub(crate) struct Expiries {
+    sequence: u64,
+    heap: BinaryHeap<Reverse<(Instant, u64, Arc<str>)>>,
+}
+
+impl Expiries {
+    pub fn len(&self) -> usize {
+        self.heap.len()
+    }
+
+    /// Adds a key expiring at `at`, returns the id of the push
+    pub fn push(&mut self, name: &Arc<str>, at: Instant) -> u64 {
+        self.sequence += 1;
+        self.heap
+            .push(Reverse((at, self.sequence, Arc::clone(name))));
+        self.sequence
+    }
+
+    /// Pushes a popped key again, with the same id
+    pub fn push_again(&mut self, id: u64, name: Arc<str>, at: Instant) {
+        self.heap.push(Reverse((at, id, name)));
+    }
+
+    /// Whether a key expires at or before `at`
+    pub fn has_due(&self, at: Instant) -> bool {
+        matches!(self.heap.peek(), Some(Reverse((expiry, _, _))) if *expiry <= at)
+    }
+
+    /// Pops the next key expiring at or before `at`, with the id of its push
+    pub fn pop_due(&mut self, at: Instant) -> Option<(u64, Arc<str>)> {
+        if !self.has_due(at) {
+            return None;
+        }
+
+        self.heap.pop().map(|Reverse((_, id, name))| (id, name))
+    }
+
+    pub fn clear(&mut self) {
+        self.heap.clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_expiries() {
+        let now = Instant::now();
+        let mut expiries = Expiries::default();
+        let (foo_name, bar_name): (Arc<str>, Arc<str>) = (Arc::from("foo"), Arc::from("bar"));
+
+        let foo = expiries.push(&foo_name, now + Duration::from_secs(2));
+        let bar = expiries.push(&bar_name, now + Duration::from_secs(1));
+        assert_ne!(foo, bar);
+
+        assert!(!expiries.has_due(now));
+        assert_eq!(expiries.pop_due(now), None);
+
+        let later = now + Duration::from_secs(2);
+        assert_eq!(expiries.pop_due(later), Some((bar, Arc::clone(&bar_name))));
+        expiries.push_again(bar, bar_name, now + Duration::from_secs(3));
+        assert_eq!(expiries.pop_due(later), Some((foo, foo_name)));
+        assert_eq!(expiries.pop_due(later), None);
+        assert_eq!(expiries.len(), 1);
+    }
+}
@@ -1 +1,2 @@
 pub mod memcache;
+pub mod memcache_binary;
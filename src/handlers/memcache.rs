@@ -1,4 +1,11 @@
-use std::str;
+use std::cmp;
+use std::fmt;
+use std::process;
+use std::slice;
+use std::str::{self, FromStr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::MutexGuard;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use lazy_static::lazy_static;
 
@@ -7,91 +14,701 @@ use regex::Regex;
 use async_std::prelude::*;
 use async_std::sync::Arc;
 
-use async_std::io::{Read, Write};
-use futures::lock::Mutex;
+use async_std::io::{self, Read, Write};
 
-use crate::{Ratelimit, RatelimitCollection};
+use crate::composite;
+use crate::handlers::memcache_binary::{self, Header, HEADER_SIZE};
+use crate::{
+    Algorithm, Clock, CollectionError, ConcurrentCollection, ConcurrentRatelimit, Decision, Limit,
+    Ratelimit, RatelimitCollection, Rule, Rules, SpecifiedKey, Stats, SystemClock,
+};
+
+/// Size of the reads from the stream
+const READ_SIZE: usize = 4096;
+/// Maximum length of a command line, longer lines are refused
+const MAX_LINE: usize = 8192;
+/// Maximum size of the data block of a storage command, as the default item size of memcached
+const MAX_DATA: usize = 1024 * 1024;
+/// Maximum cost of a hit, the sliding log storing a timestamp per unit of cost
+pub(crate) const MAX_COST: u32 = 1_000_000;
+/// Maximum number of seconds of "touch", larger exptimes being unix times for memcached
+const MAX_TOUCH: u64 = 30 * 86400;
+
+/// A locked shard of a ratelimit, with the keys of "incr_all" it holds and their count
+type LockedKeys<'a, 'k, C> = (MutexGuard<'a, Ratelimit<C>>, Vec<&'k (&'k str, u32)>);
 
 pub trait AsyncStream: Read + Write + Unpin {}
 impl<T: Read + Write + Unpin> AsyncStream for T {}
 
+/// Where the replies are written, the stream or a sink for "noreply" commands
+pub trait AsyncWriter: Write + Unpin {}
+impl<T: Write + Unpin> AsyncWriter for T {}
+
 enum Command {
-    Incr(String),
+    /// `incr <key> [<cost>]`
+    Incr(String, u32),
+    /// `decr <key> [<cost>]`, refunds hits
+    Decr(String, u32),
+    /// `get <key> [<key> ...]`
+    Get(Vec<String>),
+    /// `incr_all <key> [<key> ...]`
+    IncrAll(Vec<String>),
+    /// `delete <key>`
+    Delete(String),
+    /// `touch <key> <seconds>`, limits the key for that long
+    Touch(String, u64),
+    /// `set`, `add`, `replace`, `append`, `prepend` or `cas` with the size of their data block
+    Store(usize),
+    Stats,
+    Version,
+    Quit,
+    /// `mg <key> <flag>*`, as "get"
+    MetaGet(String, MetaFlags),
+    /// `ma <key> <flag>*`, as "incr", or "decr" in decrement mode
+    MetaArithmetic(String, MetaFlags),
+    /// `md <key> <flag>*`, as "delete"
+    MetaDelete(String, MetaFlags),
+    /// `mn`, replied at once, marking the end of the replies of quiet commands
+    MetaNoop,
+}
+
+/// Flags of the meta commands, the others being ignored
+#[derive(Debug, Default, PartialEq, Eq)]
+struct MetaFlags {
+    /// `v`, reply the value: the hits remaining, or the value of "incr" for "ma"
+    value: bool,
+    /// `t`, reply the number of seconds until the limits are fully reset
+    ttl: bool,
+    /// `k`, reply the key
+    key: bool,
+    /// `q`, skip the replies without value, other than errors
+    quiet: bool,
+    /// `O<token>`, an opaque token replied as is
+    opaque: Option<String>,
+    /// `D<delta>`, the cost of the hit of "ma", 1 if omitted
+    delta: Option<u32>,
+    /// `MD` or `M-`, "ma" refunds hits instead of hitting the key
+    decrement: bool,
+}
+
+/// Why a command line was refused
+#[derive(Debug, PartialEq, Eq)]
+enum InputError {
+    /// Not a command of the protocol, replied with "ERROR"
+    Unknown,
+    /// A command with invalid arguments, replied with "CLIENT_ERROR"
+    Invalid,
+    /// An opaque token longer than 32 bytes, replied with "CLIENT_ERROR"
+    OpaqueTooLong,
+}
+
+/// What follows a command line, or a request of the binary protocol
+pub(crate) enum Next {
+    /// The next command line
+    Line,
+    /// A data block of that many bytes, including its terminator, to skip
+    Data(usize),
+    /// Nothing, the connection is closed
+    Quit,
+}
+
+/// Protocol of a connection, detected on its first byte
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Protocol {
+    Text,
+    Binary,
 }
 
-pub struct StreamHandler {
-    ratelimit: Arc<Mutex<Ratelimit>>,
-    ratelimit_collection: Arc<Mutex<RatelimitCollection>>,
+pub struct StreamHandler<C: Clock = SystemClock> {
+    ratelimit: Arc<ConcurrentRatelimit<C>>,
+    ratelimit_collection: Arc<ConcurrentCollection<C>>,
+    rules: Arc<Rules<C>>,
+    stats: Arc<Stats>,
+    retry_after: bool,
+    dynamic_limits: bool,
+    protocol: Option<Protocol>,
+    /// Bytes read but not handled yet, the beginning of an incomplete line or request
+    input: Vec<u8>,
+    /// Whether the rest of a line too long is being skipped
+    skipping: bool,
+    /// Bytes of a data block still to be skipped
+    data: usize,
 }
 
 /// StreamHandler
 /// Handles a single TCP stream
-impl StreamHandler {
+impl<C: Clock> StreamHandler<C> {
     pub fn new(
-        ratelimit: &Arc<Mutex<Ratelimit>>,
-        ratelimit_collection: &Arc<Mutex<RatelimitCollection>>,
-    ) -> StreamHandler {
+        ratelimit: &Arc<ConcurrentRatelimit<C>>,
+        ratelimit_collection: &Arc<ConcurrentCollection<C>>,
+    ) -> StreamHandler<C> {
         StreamHandler {
             ratelimit: ratelimit.clone(),
             ratelimit_collection: ratelimit_collection.clone(),
+            rules: Arc::new(Rules::default()),
+            stats: Arc::new(Stats::new()),
+            retry_after: false,
+            dynamic_limits: true,
+            protocol: None,
+            input: vec![],
+            skipping: false,
+            data: 0,
+        }
+    }
+
+    /// When enabled, a limited "incr" replies with the number of milliseconds
+    /// until the next hit is allowed, instead of 1
+    pub fn with_retry_after(mut self, enabled: bool) -> StreamHandler<C> {
+        self.retry_after = enabled;
+        self
+    }
+
+    /// When disabled, keys such as "1/2_foo" are plain keys of the default limit
+    /// instead of specifying their own limit
+    pub fn with_dynamic_limits(mut self, enabled: bool) -> StreamHandler<C> {
+        self.dynamic_limits = enabled;
+        self
+    }
+
+    /// Keys not specifying their own limit are limited by the first rule they match,
+    /// the default limit applying to the keys matching none
+    pub fn with_rules(mut self, rules: &Arc<Rules<C>>) -> StreamHandler<C> {
+        self.rules = rules.clone();
+        self
+    }
+
+    /// Counters reported by "stats", shared with the other connections of the server
+    pub fn with_stats(mut self, stats: &Arc<Stats>) -> StreamHandler<C> {
+        self.stats = stats.clone();
+        self
+    }
+
+    /// The limits specified by the key, if dynamic limits are enabled
+    fn specification(&self, keyname: &str) -> Option<(Vec<Limit>, String)> {
+        if self.dynamic_limits {
+            parse_specification(keyname)
+        } else {
+            None
+        }
+    }
+
+    /// The stacked ratelimits of a key not specifying its own limits
+    fn ratelimits(&self, keyname: &str) -> &[ConcurrentRatelimit<C>] {
+        match self.rules.find(keyname) {
+            Some(rule) => rule.limits(),
+            None => slice::from_ref(&self.ratelimit),
+        }
+    }
+
+    /// The ratelimits of the rule at `index`, the default one past the last rule
+    fn ratelimits_at(&self, index: usize) -> &[ConcurrentRatelimit<C>] {
+        self.rules
+            .get(index)
+            .map_or(slice::from_ref(&self.ratelimit), Rule::limits)
+    }
+
+    /// An "error" response to a command line that could not be handled
+    async fn reply_err(&self, error: InputError, stream: &mut impl AsyncWriter) -> bool {
+        match error {
+            InputError::Unknown => self.write("ERROR\r\n", stream).await,
+            InputError::Invalid => {
+                self.write("CLIENT_ERROR bad command line format\r\n", stream)
+                    .await
+            }
+            InputError::OpaqueTooLong => {
+                self.write("CLIENT_ERROR opaque token too long\r\n", stream)
+                    .await
+            }
         }
     }
 
-    /// An "OK" response, request was within the limits (ironically 0)
-    async fn reply_ok(&self, stream: &mut impl AsyncStream) -> bool {
-        self.write("0\r\n", stream).await
+    /// An error response to a command refused by the limits collection,
+    /// "SERVER_ERROR" when the server is out of resources, "CLIENT_ERROR" otherwise
+    async fn reply_collection_err(
+        &self,
+        error: CollectionError,
+        stream: &mut impl AsyncWriter,
+    ) -> bool {
+        let kind = match error {
+            CollectionError::TooManyLimits { .. } => "SERVER_ERROR",
+            CollectionError::Invalid(_)
+            | CollectionError::NotAllowed { .. }
+            | CollectionError::CostExceedsLimit { .. } => "CLIENT_ERROR",
+        };
+        self.write(&format!("{} {}\r\n", kind, error), stream).await
     }
 
-    /// An "not OK" response, request was outside the limits and should be limited (ironically 1)
-    async fn reply_ko(&self, stream: &mut impl AsyncStream) -> bool {
-        self.write("1\r\n", stream).await
+    /// A memcache "VALUE" item, for retrieval commands, followed by other items or "END"
+    fn value(key: &str, value: &str) -> String {
+        format!("VALUE {} 0 {}\r\n{}\r\n", key, value.len(), value)
     }
 
-    /// An "error" response, the request was malformed or using a bad syntax
-    async fn reply_err(&self, stream: &mut impl AsyncStream) -> bool {
-        self.write("ERR\r\n", stream).await
+    /// The reply of a meta command, with the flags requested
+    ///
+    /// With a `decision`, `value` is replied if requested, `code` otherwise, such as "NS"
+    /// if the hit was refused. Without, `code` is replied, such as "NF" if the key is unknown.
+    /// Quiet commands do not reply "HD", the code of success
+    fn meta_reply(
+        keyname: &str,
+        flags: &MetaFlags,
+        decision: Option<&Decision>,
+        value: &str,
+        code: &str,
+    ) -> String {
+        let mut returned = String::new();
+        if let (true, Some(decision)) = (flags.ttl, decision) {
+            let reset_after = decision.reset_after;
+            let seconds = reset_after.as_secs() + u64::from(reset_after.subsec_nanos() > 0);
+            returned.push_str(&format!(" t{}", seconds));
+        }
+        if flags.key {
+            returned.push_str(&format!(" k{}", keyname));
+        }
+        if let Some(opaque) = &flags.opaque {
+            returned.push_str(&format!(" O{}", opaque));
+        }
+
+        match decision {
+            Some(_) if flags.value => {
+                format!("VA {}{}\r\n{}\r\n", value.len(), returned, value)
+            }
+            _ if flags.quiet && code == "HD" => String::new(),
+            _ => format!("{}{}\r\n", code, returned),
+        }
     }
 
     /// Flush the binary response
-    async fn write(&self, response: &str, stream: &mut impl AsyncStream) -> bool {
-        stream.write(response.as_bytes()).await.is_ok() && stream.flush().await.is_ok()
+    async fn write(&self, response: &str, stream: &mut impl AsyncWriter) -> bool {
+        stream.write_all(response.as_bytes()).await.is_ok() && stream.flush().await.is_ok()
+    }
+
+    /// The value replied to a hit: "OK" (ironically 0) if it was within the limits,
+    /// otherwise "not OK" (ironically 1), or the number of milliseconds until the next hit
+    /// is allowed (at least 1, as 0 means OK)
+    fn outcome(&self, decision: Result<(), Option<Duration>>) -> u64 {
+        let counter = match decision {
+            Ok(()) => &self.stats.hits_allowed,
+            Err(_) => &self.stats.hits_limited,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+
+        match decision {
+            Ok(()) => 0,
+            Err(None) => 1,
+            Err(Some(retry_after)) => cmp::max(
+                1,
+                u64::try_from(retry_after.as_millis()).unwrap_or(u64::MAX),
+            ),
+        }
     }
 
-    /// Handles an "incr" command
-    /// Will write the response on the output stream
+    /// Hits a key, "incr", see `outcome` for the value returned
     /// Can return an error in case the keyname is invalid
-    async fn handle_incr(
+    pub(crate) fn incr(&self, keyname: &str, cost: u32) -> Result<u64, CollectionError> {
+        if self.retry_after {
+            return self.incr_decision(keyname, cost).map(|(value, _)| value);
+        }
+
+        self.stats.cmd_incr.fetch_add(1, Ordering::Relaxed);
+        let allowed = self
+            .with_ratelimits(keyname, |ratelimits, name| {
+                Self::check_cost(ratelimits, cost)?;
+                Ok(composite::hit(ratelimits, name, cost))
+            })
+            .and_then(|allowed| allowed)?;
+
+        Ok(self.outcome(if allowed { Ok(()) } else { Err(None) }))
+    }
+
+    /// Locks the shards holding a key not specifying its own limits, in each of its ratelimits
+    fn lock(&self, keyname: &str) -> Vec<MutexGuard<'_, Ratelimit<C>>> {
+        self.ratelimits(keyname)
+            .iter()
+            .map(|ratelimit| ratelimit.lock(keyname))
+            .collect()
+    }
+
+    /// Runs `f` on the stacked ratelimits of a key, with the key without its specification
+    fn with_ratelimits<T>(
         &self,
         keyname: &str,
-        stream: &mut impl AsyncStream,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let within_limits = match parse_specification(keyname) {
-            Some((hits, duration, keyname)) => {
-                let mut meta = self.ratelimit_collection.lock().await;
-                let rl = meta.get_instance(hits, duration)?;
-                rl.hit(&keyname)
+        f: impl FnOnce(&mut [&mut Ratelimit<C>], &str) -> T,
+    ) -> Result<T, CollectionError> {
+        match self.specification(keyname) {
+            Some((limits, name)) => {
+                let mut meta = self.ratelimit_collection.lock(&name);
+                Ok(f(&mut meta.get_instances(&limits)?, &name))
             }
             None => {
-                let mut ratelimit = self.ratelimit.lock().await;
-                ratelimit.hit(keyname)
+                let mut guards = self.lock(keyname);
+                let mut ratelimits: Vec<_> = guards.iter_mut().map(|guard| &mut **guard).collect();
+                Ok(f(&mut ratelimits, keyname))
             }
+        }
+    }
+
+    /// Same as `incr`, along with the state of the key after the hit, for its most
+    /// restrictive limit
+    fn incr_decision(&self, keyname: &str, cost: u32) -> Result<(u64, Decision), CollectionError> {
+        self.stats.cmd_incr.fetch_add(1, Ordering::Relaxed);
+        let decision = self
+            .with_ratelimits(keyname, |ratelimits, name| {
+                Self::check_cost(ratelimits, cost)?;
+                Ok(composite::hit_decision(ratelimits, name, cost))
+            })
+            .and_then(|decision| decision)?;
+
+        let outcome = match decision.allowed {
+            true => Ok(()),
+            false if self.retry_after => Err(Some(decision.retry_after)),
+            false => Err(None),
         };
+        Ok((self.outcome(outcome), decision))
+    }
 
-        if within_limits {
-            self.reply_ok(stream).await;
-        } else {
-            self.reply_ko(stream).await;
+    /// Refuses a cost exceeding one of the stacked limits, as no delay would allow the hit
+    fn check_cost(ratelimits: &[&mut Ratelimit<C>], cost: u32) -> Result<(), CollectionError> {
+        match ratelimits.iter().map(|rl| rl.limit()).min() {
+            Some(limit) if cost > limit => Err(CollectionError::CostExceedsLimit { cost, limit }),
+            _ => Ok(()),
         }
+    }
 
-        Ok(())
+    /// Checks the limits of a key without recording a hit, "get"
+    /// Returns the state of the key for its most restrictive limit, such as the hits remaining
+    pub(crate) fn check(&self, keyname: &str) -> Result<Decision, CollectionError> {
+        self.stats.cmd_get.fetch_add(1, Ordering::Relaxed);
+        self.with_ratelimits(keyname, |ratelimits, name| {
+            composite::check_decision(ratelimits, name, 1)
+        })
     }
 
-    /// Handles a single command (one read currently)
-    async fn handle_one(
+    /// Refunds the most recent hits of a key, "decr"
+    /// Returns the state of the key, as `check`, or None if the key is unknown
+    pub(crate) fn decr(
         &self,
+        keyname: &str,
+        cost: u32,
+    ) -> Result<Option<Decision>, CollectionError> {
+        self.stats.cmd_decr.fetch_add(1, Ordering::Relaxed);
+        self.with_ratelimits(keyname, |ratelimits, name| {
+            if composite::refund(ratelimits, name, cost) {
+                Some(composite::check_decision(ratelimits, name, 1))
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Limits a key for the given number of seconds, "touch"
+    /// Returns whether the key was known
+    pub(crate) fn touch(&self, keyname: &str, seconds: u64) -> Result<bool, CollectionError> {
+        self.stats.cmd_touch.fetch_add(1, Ordering::Relaxed);
+        let duration = Duration::from_secs(seconds);
+        self.with_ratelimits(keyname, |ratelimits, name| {
+            composite::block(ratelimits, name, duration)
+        })
+    }
+
+    /// Hits all the keys only if every one of them is within its limits, "incr_all"
+    /// See `outcome` for the value returned
+    fn incr_all(&self, keynames: &[String]) -> Result<u64, CollectionError> {
+        self.stats.cmd_incr.fetch_add(1, Ordering::Relaxed);
+
+        let mut specified = vec![];
+        // Keys not specifying their limit by index of their rule, the default ratelimit last,
+        // with the number of times they are present
+        let mut unspecified: Vec<Vec<(&str, u32)>> = vec![vec![]; self.rules.len() + 1];
+
+        for keyname in keynames {
+            if let Some(spec) = self.specification(keyname) {
+                specified.push(spec);
+                continue;
+            }
+
+            let index = self.rules.position(keyname).unwrap_or(self.rules.len());
+            let names = &mut unspecified[index];
+            match names.iter_mut().find(|(name, _)| name == keyname) {
+                Some((_, count)) => *count += 1,
+                None => names.push((keyname, 1)),
+            }
+        }
+
+        let specified: Vec<SpecifiedKey> = specified
+            .iter()
+            .flat_map(|(limits, name)| {
+                limits.iter().map(move |(hits, duration, algorithm)| {
+                    (*hits, *duration, *algorithm, name.as_str())
+                })
+            })
+            .collect();
+
+        let within_limits = {
+            // Always locked in this order: collection shards, then shards of the rules
+            // and of their stacked limits in order, then default shards
+            let mut metas = self.ratelimit_collection.lock_many(&specified);
+            let mut ratelimits: Vec<_> = unspecified
+                .iter()
+                .enumerate()
+                .filter(|(_, names)| !names.is_empty())
+                .flat_map(|(index, names)| {
+                    self.ratelimits_at(index)
+                        .iter()
+                        .flat_map(move |ratelimit| ratelimit.lock_many(names, |(name, _)| name))
+                })
+                .collect();
+
+            let mut within_limits = true;
+            for (meta, keys) in metas.iter_mut() {
+                within_limits = within_limits && meta.check_all(keys, 1)?;
+            }
+            within_limits = within_limits
+                && ratelimits.iter().all(|(ratelimit, names)| {
+                    names
+                        .iter()
+                        .all(|(name, count)| ratelimit.check_n(name, *count))
+                });
+
+            within_limits && Self::record_all(&mut metas, &mut ratelimits)?
+        };
+
+        Ok(self.outcome(if within_limits { Ok(()) } else { Err(None) }))
+    }
+
+    /// Hits the keys of "incr_all" once checked. A key may still be refused when the keys
+    /// added before it filled the bounds, the hits already recorded are then undone
+    fn record_all(
+        metas: &mut [(MutexGuard<'_, RatelimitCollection<C>>, Vec<SpecifiedKey>)],
+        ratelimits: &mut [LockedKeys<'_, '_, C>],
+    ) -> Result<bool, CollectionError> {
+        let mut recorded = vec![];
+        for (meta, keys) in metas.iter_mut() {
+            match meta.record_all(keys, 1) {
+                Ok(Some(hits)) => recorded.push(hits),
+                failed => {
+                    for ((meta, _), hits) in metas.iter_mut().zip(&recorded) {
+                        meta.undo(hits);
+                    }
+                    return failed.map(|_| false);
+                }
+            }
+        }
+
+        let mut hits = vec![];
+        let refused = ratelimits
+            .iter_mut()
+            .enumerate()
+            .any(|(index, (ratelimit, names))| {
+                names.iter().any(|&&(name, count)| {
+                    let added = !ratelimit.contains(name);
+                    let hit = ratelimit.hit_n(name, count);
+                    if hit {
+                        hits.push((index, name, count, added));
+                    }
+                    !hit
+                })
+            });
+
+        if refused {
+            for ((meta, _), hits) in metas.iter_mut().zip(&recorded) {
+                meta.undo(hits);
+            }
+            for (index, name, count, added) in hits {
+                ratelimits[index].0.undo_hit(name, count, added);
+            }
+        }
+
+        Ok(!refused)
+    }
+
+    /// Clears the history of a key, "delete", returns whether the key was known
+    pub(crate) fn delete(&self, keyname: &str) -> bool {
+        self.stats.cmd_delete.fetch_add(1, Ordering::Relaxed);
+        // Every limit is reset, even once the key was found in one
+        let deleted: usize = match self.specification(keyname) {
+            Some((limits, name)) => {
+                let mut meta = self.ratelimit_collection.lock(&name);
+                limits
+                    .iter()
+                    .map(|(hits, duration, algorithm)| {
+                        usize::from(meta.reset(*hits, *duration, *algorithm, &name))
+                    })
+                    .sum::<usize>()
+            }
+            None => self
+                .lock(keyname)
+                .iter_mut()
+                .map(|ratelimit| usize::from(ratelimit.reset(keyname)))
+                .sum(),
+        };
+
+        deleted > 0
+    }
+
+    /// The counters of the server, "stats", as pairs of name and value
+    pub(crate) fn stats(&self) -> Vec<(&'static str, String)> {
+        let counters = &self.stats;
+        let rules = || self.rules.iter().flat_map(Rule::limits);
+        let items = self.ratelimit.len()
+            + self.ratelimit_collection.len()
+            + rules().map(|rl| rl.len()).sum::<usize>();
+        let evictions = self.ratelimit.evictions()
+            + self.ratelimit_collection.evictions()
+            + rules().map(|rl| rl.evictions()).sum::<u64>();
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+
+        let mut stats = vec![];
+        let mut stat = |name, value: &dyn fmt::Display| stats.push((name, value.to_string()));
+        let counter = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+
+        stat("pid", &process::id());
+        stat("uptime", &counters.uptime().as_secs());
+        stat("time", &time.as_secs());
+        stat("version", &env!("CARGO_PKG_VERSION"));
+        stat("curr_connections", &counter(&counters.curr_connections));
+        stat("total_connections", &counter(&counters.total_connections));
+        stat("cmd_get", &counter(&counters.cmd_get));
+        stat("cmd_incr", &counter(&counters.cmd_incr));
+        stat("cmd_decr", &counter(&counters.cmd_decr));
+        stat("cmd_delete", &counter(&counters.cmd_delete));
+        stat("cmd_touch", &counter(&counters.cmd_touch));
+        stat("hits_allowed", &counter(&counters.hits_allowed));
+        stat("hits_limited", &counter(&counters.hits_limited));
+        stat("curr_items", &items);
+        stat("evictions", &evictions);
+
+        stats
+    }
+
+    /// Handles a single command line, without its line terminator
+    async fn handle_line(&self, line: &[u8], stream: &mut impl AsyncWriter) -> Next {
+        let (command, noreply) = match read_input(line) {
+            Ok(x) => x,
+            Err(error) => {
+                self.reply_err(error, stream).await;
+                return Next::Line;
+            }
+        };
+
+        if noreply {
+            self.handle_command(command, &mut io::sink()).await
+        } else {
+            self.handle_command(command, stream).await
+        }
+    }
+
+    /// Handles a parsed command, writing its reply
+    async fn handle_command(&self, command: Command, stream: &mut impl AsyncWriter) -> Next {
+        // Replies of the commands on a key, which may be unknown
+        let found = |found: bool, reply: &str| match found {
+            true => format!("{}\r\n", reply),
+            false => "NOT_FOUND\r\n".to_string(),
+        };
+
+        let response = match command {
+            Command::Incr(ref keyname, cost) => self
+                .incr(keyname, cost)
+                .map(|value| format!("{}\r\n", value)),
+            Command::Decr(ref keyname, cost) => {
+                self.decr(keyname, cost).map(|decision| match decision {
+                    Some(decision) => found(true, &decision.remaining.to_string()),
+                    None => found(false, "NOT_FOUND"),
+                })
+            }
+            Command::Get(ref keynames) => keynames
+                .iter()
+                .map(|keyname| {
+                    self.check(keyname)
+                        .map(|decision| Self::value(keyname, &decision.remaining.to_string()))
+                })
+                .collect::<Result<String, _>>()
+                .map(|values| values + "END\r\n"),
+            Command::IncrAll(ref keynames) => self
+                .incr_all(keynames)
+                .map(|value| format!("{}\r\n", value)),
+            Command::Delete(ref keyname) => Ok(found(self.delete(keyname), "DELETED")),
+            Command::Touch(ref keyname, seconds) => self
+                .touch(keyname, seconds)
+                .map(|touched| found(touched, "TOUCHED")),
+            // Nothing can be stored, the data block is skipped as it is read
+            Command::Store(bytes) => match bytes.checked_add(2) {
+                Some(size) if bytes <= MAX_DATA => {
+                    self.write("NOT_STORED\r\n", stream).await;
+                    return Next::Data(size);
+                }
+                Some(size) => {
+                    self.write("SERVER_ERROR object too large for cache\r\n", stream)
+                        .await;
+                    return Next::Data(size);
+                }
+                // The end of the block cannot be found, the connection is closed
+                None => {
+                    self.write("SERVER_ERROR object too large for cache\r\n", stream)
+                        .await;
+                    return Next::Quit;
+                }
+            },
+            Command::Stats => {
+                let mut response: String = self
+                    .stats()
+                    .iter()
+                    .map(|(name, value)| format!("STAT {} {}\r\n", name, value))
+                    .collect();
+                response.push_str("END\r\n");
+                Ok(response)
+            }
+            Command::Version => Ok(format!("VERSION {}\r\n", env!("CARGO_PKG_VERSION"))),
+            Command::Quit => return Next::Quit,
+            Command::MetaGet(ref keyname, ref flags) => self.check(keyname).map(|decision| {
+                Self::meta_reply(
+                    keyname,
+                    flags,
+                    Some(&decision),
+                    &decision.remaining.to_string(),
+                    "HD",
+                )
+            }),
+            Command::MetaArithmetic(ref keyname, ref flags) if flags.decrement => self
+                .decr(keyname, flags.delta.unwrap_or(1))
+                .map(|decision| match decision {
+                    Some(decision) => Self::meta_reply(
+                        keyname,
+                        flags,
+                        Some(&decision),
+                        &decision.remaining.to_string(),
+                        "HD",
+                    ),
+                    None => Self::meta_reply(keyname, flags, None, "", "NF"),
+                }),
+            Command::MetaArithmetic(ref keyname, ref flags) => self
+                .incr_decision(keyname, flags.delta.unwrap_or(1))
+                .map(|(value, decision)| {
+                    let code = if decision.allowed { "HD" } else { "NS" };
+                    Self::meta_reply(keyname, flags, Some(&decision), &value.to_string(), code)
+                }),
+            Command::MetaDelete(ref keyname, ref flags) => {
+                let code = if self.delete(keyname) { "HD" } else { "NF" };
+                Ok(Self::meta_reply(keyname, flags, None, "", code))
+            }
+            Command::MetaNoop => Ok("MN\r\n".to_string()),
+        };
+
+        match response {
+            Ok(response) => self.write(&response, stream).await,
+            Err(error) => self.reply_collection_err(error, stream).await,
+        };
+        Next::Line
+    }
+
+    /// Handles a single read, running every complete command in order.
+    /// An incomplete last line, or request, is kept until the next read
+    pub(crate) async fn handle_one(
+        &mut self,
         stream: &mut impl AsyncStream,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let mut buffer = [0; 512];
+        let mut buffer = [0; READ_SIZE];
         let read = stream.read(&mut buffer).await?;
 
         // Empty read: close the connection
@@ -99,29 +716,116 @@ impl StreamHandler {
             return Err("".into());
         }
 
-        let command = match read_input(buffer, read) {
-            Ok(x) => x,
-            Err(_) => {
-                self.reply_err(stream).await;
-                return Ok(());
+        let mut data = &buffer[..read];
+        if self.skipping {
+            match data.iter().position(|&b| b == b'\n') {
+                Some(end) => {
+                    self.skipping = false;
+                    data = &data[end + 1..];
+                }
+                None => return Ok(()),
             }
-        };
+        }
+        self.input.extend_from_slice(data);
 
-        match command {
-            Command::Incr(ref keyname) => {
-                if self.handle_incr(keyname, stream).await.is_err() {
-                    self.reply_err(stream).await;
-                }
-            } // Unknown command
-              // _ => {
-              //     self.reply_err().await;
-              // }
+        let protocol = *self.protocol.get_or_insert_with(|| match data[0] {
+            memcache_binary::MAGIC_REQUEST => Protocol::Binary,
+            _ => Protocol::Text,
+        });
+        match protocol {
+            Protocol::Text => self.handle_lines(stream).await,
+            Protocol::Binary => self.handle_requests(stream).await,
+        }
+    }
+
+    /// Handles every complete command line of the input
+    async fn handle_lines(
+        &mut self,
+        stream: &mut impl AsyncStream,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut start = 0;
+        loop {
+            let skipped = cmp::min(self.data, self.input.len() - start);
+            self.data -= skipped;
+            start += skipped;
+
+            let end = match self.input[start..].iter().position(|&b| b == b'\n') {
+                Some(end) => end,
+                None => break,
+            };
+
+            // Lines are terminated by "\r\n", or by "\n" alone as sent by some tools
+            let line = &self.input[start..start + end];
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            let next = self.handle_line(line, stream).await;
+            start += end + 1;
+
+            match next {
+                Next::Line => {}
+                Next::Data(size) => self.data = size,
+                Next::Quit => return Err("".into()),
+            }
+        }
+        self.input.drain(..start);
+
+        if self.input.len() > MAX_LINE {
+            self.input.clear();
+            self.skipping = true;
+            self.write("CLIENT_ERROR line too long\r\n", stream).await;
+        }
+
+        Ok(())
+    }
+
+    /// Handles every complete request of the binary protocol in the input
+    async fn handle_requests(
+        &mut self,
+        stream: &mut impl AsyncStream,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut start = 0;
+        loop {
+            let skipped = cmp::min(self.data, self.input.len() - start);
+            self.data -= skipped;
+            start += skipped;
+
+            let input = &self.input[start..];
+            if input.len() < HEADER_SIZE {
+                break;
+            }
+            // Not a request, the following ones cannot be found
+            let header = Header::parse(input).ok_or("invalid request")?;
+
+            // As command lines, the bodies are bounded, a body too large is skipped
+            let length = header.body_length();
+            if length > MAX_LINE {
+                memcache_binary::reply_too_large(&header, stream).await;
+                start += HEADER_SIZE;
+                self.data = length;
+                continue;
+            }
+            if input.len() < HEADER_SIZE + length {
+                break;
+            }
+
+            let body = &input[HEADER_SIZE..HEADER_SIZE + length];
+            let next = memcache_binary::handle_request(self, &header, body, stream).await;
+            start += HEADER_SIZE + length;
+
+            match next {
+                Next::Line => {}
+                Next::Data(size) => self.data = size,
+                Next::Quit => return Err("".into()),
+            }
         }
+        self.input.drain(..start);
 
         Ok(())
     }
 
-    pub async fn main(&self, stream: &mut impl AsyncStream) {
+    pub async fn main(&mut self, stream: &mut impl AsyncStream) {
+        self.stats.curr_connections.fetch_add(1, Ordering::Relaxed);
+        self.stats.total_connections.fetch_add(1, Ordering::Relaxed);
+
         #[cfg(test)]
         let mut tmax = 1_000;
 
@@ -139,72 +843,246 @@ impl StreamHandler {
                 break;
             }
         }
+
+        self.stats.curr_connections.fetch_sub(1, Ordering::Relaxed);
     }
 }
 
-fn read_input(buffer: [u8; 512], len: usize) -> Result<Command, ()> {
-    let input = match str::from_utf8(&buffer[0..len]) {
-        Ok(v) => v,
-        Err(_) => return Err(()),
+/// Parse a command line returning: `(command, noreply)`, the reply being skipped
+/// when the command ends with "noreply"
+fn read_input(line: &[u8]) -> Result<(Command, bool), InputError> {
+    let input = str::from_utf8(line).map_err(|_| InputError::Invalid)?;
+    let mut split = input.split(' ').map(str::trim).filter(|x| !x.is_empty());
+
+    let command = split.next().ok_or(InputError::Unknown)?;
+    let mut args: Vec<&str> = split.collect();
+
+    // Supported by the commands changing a single key, "incr noreply" still hits the key "noreply"
+    let noreply = matches!(
+        command,
+        "incr"
+            | "decr"
+            | "delete"
+            | "touch"
+            | "set"
+            | "add"
+            | "replace"
+            | "append"
+            | "prepend"
+            | "cas"
+    ) && args.len() > 1
+        && args.last() == Some(&"noreply");
+    if noreply {
+        args.pop();
     }
-    .trim();
 
-    let (command, key) = {
-        let mut split = input.split(' ');
+    let command = parse_command(command, &args)?;
+    Ok((command, noreply))
+}
+
+fn parse_command(command: &str, args: &[&str]) -> Result<Command, InputError> {
+    match (command, args) {
+        // The increment value is the cost of the hit, 1 if omitted
+        ("incr", [key]) => Ok(Command::Incr(key.to_string(), 1)),
+        ("incr", [key, cost]) => Ok(Command::Incr(key.to_string(), parse_cost(cost)?)),
+        ("decr", [key]) => Ok(Command::Decr(key.to_string(), 1)),
+        ("decr", [key, cost]) => Ok(Command::Decr(key.to_string(), parse_cost(cost)?)),
+        ("get" | "gets", keys) if !keys.is_empty() => {
+            Ok(Command::Get(keys.iter().map(|x| x.to_string()).collect()))
+        }
+        ("incr_all", keys) if !keys.is_empty() => Ok(Command::IncrAll(
+            keys.iter().map(|x| x.to_string()).collect(),
+        )),
+        // Some clients still send the deprecated time, always 0
+        ("delete", [key]) | ("delete", [key, "0"]) => Ok(Command::Delete(key.to_string())),
+        ("touch", [key, seconds]) => match number(seconds)? {
+            seconds if seconds <= MAX_TOUCH => Ok(Command::Touch(key.to_string(), seconds)),
+            _ => Err(InputError::Invalid),
+        },
+        ("set" | "add" | "replace" | "append" | "prepend", [_, _, _, bytes])
+        | ("cas", [_, _, _, bytes, _]) => Ok(Command::Store(number(bytes)?)),
+        ("mg", [key, flags @ ..]) => Ok(Command::MetaGet(key.to_string(), meta_flags(flags)?)),
+        ("ma", [key, flags @ ..]) => {
+            Ok(Command::MetaArithmetic(key.to_string(), meta_flags(flags)?))
+        }
+        ("md", [key, flags @ ..]) => Ok(Command::MetaDelete(key.to_string(), meta_flags(flags)?)),
+        ("mn", []) => Ok(Command::MetaNoop),
+        ("stats", []) => Ok(Command::Stats),
+        ("version", []) => Ok(Command::Version),
+        ("quit", []) => Ok(Command::Quit),
         (
-            match split.next() {
-                Some(x) => x.trim(),
-                None => return Err(()),
-            },
-            match split.next() {
-                Some(x) => x.trim(),
-                None => return Err(()),
-            },
-        )
-    };
+            "incr" | "decr" | "get" | "gets" | "incr_all" | "delete" | "touch" | "set" | "add"
+            | "replace" | "append" | "prepend" | "cas" | "stats" | "version" | "quit" | "mg" | "ma"
+            | "md" | "mn",
+            _,
+        ) => Err(InputError::Invalid),
+        _ => Err(InputError::Unknown),
+    }
+}
 
-    match command {
-        "incr" => Ok(Command::Incr(String::from(key))),
-        _ => Err(()),
+/// Parse the flags of a meta command, each being a letter optionally followed by a token
+fn meta_flags(flags: &[&str]) -> Result<MetaFlags, InputError> {
+    let mut parsed = MetaFlags::default();
+
+    for flag in flags {
+        let mut chars = flag.chars();
+        let name = chars.next().ok_or(InputError::Invalid)?;
+        let token = chars.as_str();
+
+        match name {
+            'v' => parsed.value = true,
+            't' => parsed.ttl = true,
+            'k' => parsed.key = true,
+            'q' => parsed.quiet = true,
+            'O' if token.len() > 32 => return Err(InputError::OpaqueTooLong),
+            'O' => parsed.opaque = Some(token.to_string()),
+            'D' => parsed.delta = Some(parse_cost(token)?),
+            'M' => {
+                parsed.decrement = match token {
+                    "I" | "i" | "+" => false,
+                    "D" | "d" | "-" => true,
+                    _ => return Err(InputError::Invalid),
+                }
+            }
+            // Keys encoded in base64 are not supported
+            'b' => return Err(InputError::Invalid),
+            name if name.is_ascii_alphabetic() => {}
+            _ => return Err(InputError::Invalid),
+        }
     }
+
+    Ok(parsed)
+}
+
+/// Parse a numeric argument of a command
+fn number<T: FromStr>(value: &str) -> Result<T, InputError> {
+    value.parse().map_err(|_| InputError::Invalid)
 }
 
-/// Parse a specification returning: `(hits, duration, keyname)`
+/// Parse the cost of a hit, at least 1 as an empty hit would only take room, at most `MAX_COST`
+fn parse_cost(value: &str) -> Result<u32, InputError> {
+    number(value).and_then(|cost| match cost {
+        1..=MAX_COST => Ok(cost),
+        _ => Err(InputError::Invalid),
+    })
+}
+
+/// Parse a specification returning: `(limits, keyname)`, each limit being
+/// `(hits, duration, algorithm)`
+///
+/// Several limits can be stacked on the key, separated by commas, see `parse_limit`
 ///
 /// ## Example
 ///
 /// ```ignored
-/// let keyname = "1/2_foo";
+/// let keyname = "10/1,300/60_foo";
 /// let result = parse_specification(keyname);
-/// assert_eq!(Some((1, 2_000, "foo")), result);
+/// assert_eq!(
+///     Some((vec![(10, 1_000, Algorithm::SlidingLog), (300, 60_000, Algorithm::SlidingLog)], "foo")),
+///     result
+/// );
+/// ```
+fn parse_specification(keyname: &str) -> Option<(Vec<Limit>, String)> {
+    let (specification, keyname) = keyname.split_once('_')?;
+    if keyname.is_empty() {
+        return None;
+    }
+
+    let limits = specification
+        .split(',')
+        .map(parse_limit)
+        .collect::<Option<_>>()?;
+
+    Some((limits, keyname.to_string()))
+}
+
+/// Parse the specification of a limit returning: `(hits, duration, algorithm)`
+///
+/// The duration is in seconds by default, a unit can be appended to it:
+/// `ms`, `s`, `m`, `h` or `d`. It may also have a decimal part, as long as
+/// it amounts to a whole number of milliseconds
+///
+/// The algorithm is optional and follows the duration, after a colon,
+/// see `Algorithm::from_spec`
+///
+/// ## Example
+///
+/// ```ignored
+/// let result = parse_limit("1/2");
+/// assert_eq!(Some((1, 2_000, Algorithm::SlidingLog)), result);
+///
+/// let result = parse_limit("5/200ms");
+/// assert_eq!(Some((5, 200, Algorithm::SlidingLog)), result);
+///
+/// let result = parse_limit("10/60:tb5");
+/// assert_eq!(Some((10, 60_000, Algorithm::TokenBucket { burst: 5 })), result);
 /// ```
-fn parse_specification(keyname: &str) -> Option<(u32, u32, String)> {
+fn parse_limit(specification: &str) -> Option<Limit> {
     lazy_static! {
-        static ref RE: Regex = Regex::new(r"^(\d+)/(\d+)_(.+)").unwrap();
+        static ref RE: Regex =
+            Regex::new(r"^(\d+)/(\d+)(?:\.(\d+))?(ms|s|m|h|d)?(?::([a-z]+)(\d+)?)?$").unwrap();
     }
 
-    let caps = RE.captures(keyname)?;
+    let caps = RE.captures(specification)?;
     let hits = caps.get(1)?.as_str();
-    let seconds = caps.get(2)?.as_str();
-    let keyname = caps.get(3)?.as_str().to_string();
+    let value = caps.get(2)?.as_str();
 
-    let hits = hits.parse();
-    let seconds = seconds.parse::<u32>();
+    let hits = hits.parse().ok()?;
+    let duration = parse_duration(
+        value,
+        caps.get(3).map(|x| x.as_str()),
+        caps.get(4).map(|x| x.as_str()),
+    )?;
 
-    if let (Ok(hits), Ok(seconds)) = (hits, seconds) {
-        Some((hits, seconds * 1000, keyname))
-    } else {
-        None
-    }
+    let algorithm = match caps.get(5) {
+        Some(name) => {
+            let arg = match caps.get(6) {
+                Some(arg) => Some(arg.as_str().parse().ok()?),
+                None => None,
+            };
+            Algorithm::from_spec(name.as_str(), arg, hits)?
+        }
+        None => Algorithm::SlidingLog,
+    };
+
+    Some((hits, duration, algorithm))
 }
 
+/// Parse a duration to milliseconds, from its integer part, optional decimal part and unit
+fn parse_duration(value: &str, decimals: Option<&str>, unit: Option<&str>) -> Option<u64> {
+    let unit: u64 = match unit {
+        Some("ms") => 1,
+        None | Some("s") => 1_000,
+        Some("m") => 60_000,
+        Some("h") => 3_600_000,
+        Some("d") => 86_400_000,
+        _ => return None,
+    };
+
+    let value = u64::from(value.parse::<u32>().ok()?) * unit;
+
+    let decimals = match decimals {
+        Some(decimals) if decimals.len() <= 9 => decimals,
+        Some(_) => return None,
+        None => return Some(value),
+    };
+
+    // Must be a whole number of milliseconds
+    let scale = 10u64.pow(u32::try_from(decimals.len()).unwrap());
+    let fraction = decimals.parse::<u64>().ok()? * unit;
+    if !fraction.is_multiple_of(scale) {
+        return None;
+    }
+
+    Some(value + fraction / scale)
+}
 
 #[cfg(test)]
 mod test {
     use super::*;
 
     use crate::testing::MockTcpStream;
-    use mock_instant::MockClock;
+    use crate::{Bounds, FailMode, KeyPattern, ManualClock};
 
     #[test]
     fn test_parse_specification() {
@@ -212,29 +1090,873 @@ mod test {
         assert_eq!(parse_specification("1zzb_zo"), None);
         assert_eq!(
             parse_specification("1/2_toto"),
-            Some((1, 2000, "toto".to_string()))
+            Some((vec![(1, 2000, Algorithm::SlidingLog)], "toto".to_string()))
         );
         assert_eq!(
             parse_specification("80/200_bar"),
-            Some((80, 200_000, "bar".to_string()))
+            Some((
+                vec![(80, 200_000, Algorithm::SlidingLog)],
+                "bar".to_string()
+            ))
         );
         assert_eq!(parse_specification("1/999999999999999_toto"), None);
         assert_eq!(parse_specification("99999999999999/99_toto"), None);
     }
 
-    #[async_std::test]
-    async fn test_base() {
-        let root = std::time::Duration::from_millis(86_400_000);
-        MockClock::set_time(root);
+    #[test]
+    fn test_parse_specification_units() {
+        let parse =
+            |keyname| parse_specification(keyname).map(|(limits, _)| (limits[0].0, limits[0].1));
 
-        let rl = Arc::new(Mutex::new(Ratelimit::new(1, 1).unwrap()));
-        let xrl = Arc::new(Mutex::new(RatelimitCollection::default()));
-        let handler = StreamHandler::new(&rl, &xrl);
+        assert_eq!(parse("5/200ms_foo"), Some((5, 200)));
+        assert_eq!(parse("1/2s_foo"), Some((1, 2_000)));
+        assert_eq!(parse("100/1m_foo"), Some((100, 60_000)));
+        assert_eq!(parse("1000/1h_foo"), Some((1000, 3_600_000)));
+        assert_eq!(parse("1/1d_foo"), Some((1, 86_400_000)));
+        assert_eq!(parse("1/1.5_foo"), Some((1, 1_500)));
+        assert_eq!(parse("1/1.5s_foo"), Some((1, 1_500)));
+        assert_eq!(parse("1/0.25m_foo"), Some((1, 15_000)));
+        assert_eq!(parse("1/1.001s_foo"), Some((1, 1_001)));
+        assert_eq!(parse("10/1h:gcra_foo"), Some((10, 3_600_000)));
 
-        let mut stream = MockTcpStream::from_rdata("incr zzz\r\n".to_string());
+        // Not a whole number of milliseconds
+        assert_eq!(parse("1/1.5ms_foo"), None);
+        assert_eq!(parse("1/1.0001s_foo"), None);
+        assert_eq!(parse("1/1y_foo"), None);
+        assert_eq!(
+            parse_specification("1/2m_foo"),
+            Some((vec![(1, 120_000, Algorithm::SlidingLog)], "foo".to_string()))
+        );
+    }
 
-        handler.handle_one(&mut stream).await.unwrap();
+    #[test]
+    fn test_parse_specification_algorithm() {
+        assert_eq!(
+            parse_specification("10/60:log_foo"),
+            Some((vec![(10, 60_000, Algorithm::SlidingLog)], "foo".to_string()))
+        );
+        assert_eq!(
+            parse_specification("10/60:tb_foo"),
+            Some((
+                vec![(10, 60_000, Algorithm::TokenBucket { burst: 10 })],
+                "foo".to_string()
+            ))
+        );
+        assert_eq!(
+            parse_specification("10/60:tb5_foo"),
+            Some((
+                vec![(10, 60_000, Algorithm::TokenBucket { burst: 5 })],
+                "foo".to_string()
+            ))
+        );
+        assert_eq!(
+            parse_specification("10/60:gcra_foo"),
+            Some((vec![(10, 60_000, Algorithm::Gcra)], "foo".to_string()))
+        );
+        assert_eq!(
+            parse_specification("10/60:sw_foo"),
+            Some((
+                vec![(10, 60_000, Algorithm::SlidingWindow)],
+                "foo".to_string()
+            ))
+        );
+        assert_eq!(
+            parse_specification("10/3600:fw_foo"),
+            Some((
+                vec![(10, 3_600_000, Algorithm::FixedWindow { offset: 0 })],
+                "foo".to_string()
+            ))
+        );
+        assert_eq!(
+            parse_specification("10/86400:fw3600_foo"),
+            Some((
+                vec![(10, 86_400_000, Algorithm::FixedWindow { offset: 3_600_000 })],
+                "foo".to_string()
+            ))
+        );
+        assert_eq!(parse_specification("10/60:nope_foo"), None);
+        assert_eq!(parse_specification("10/60:log5_foo"), None);
+    }
 
-        assert_eq!(stream.get_wdata(), "0\r\n");
+    #[test]
+    fn test_parse_specification_stacked() {
+        assert_eq!(
+            parse_specification("10/1,300/60:gcra_foo_bar"),
+            Some((
+                vec![
+                    (10, 1_000, Algorithm::SlidingLog),
+                    (300, 60_000, Algorithm::Gcra)
+                ],
+                "foo_bar".to_string()
+            ))
+        );
+        assert_eq!(parse_specification("10/1,_foo"), None);
+        assert_eq!(parse_specification("10/1,foo_bar"), None);
+        assert_eq!(parse_specification("10/1,300/60_"), None);
+    }
+
+    #[test]
+    fn test_read_input() {
+        let read = |input: &str| read_input(input.as_bytes()).map(|(command, _)| command);
+
+        assert!(matches!(read("incr foo\r\n"), Ok(Command::Incr(key, 1)) if key == "foo"));
+        assert!(matches!(read("incr foo 50\r\n"), Ok(Command::Incr(key, 50)) if key == "foo"));
+        assert!(read("incr foo bar\r\n").is_err());
+        assert!(read("incr foo -1\r\n").is_err());
+        assert!(read("incr foo 1000001\r\n").is_err());
+        assert_eq!(read("incr foo 0\r\n").err(), Some(InputError::Invalid));
+        assert_eq!(read("decr foo 0\r\n").err(), Some(InputError::Invalid));
+        assert_eq!(read("ma foo D0\r\n").err(), Some(InputError::Invalid));
+        assert!(matches!(read("get foo\r\n"), Ok(Command::Get(keys)) if keys == ["foo"]));
+        assert!(
+            matches!(read("get foo bar\r\n"), Ok(Command::Get(keys)) if keys == ["foo", "bar"])
+        );
+        assert!(matches!(
+            read("incr_all foo 1/2_bar\r\n"),
+            Ok(Command::IncrAll(keys)) if keys == ["foo", "1/2_bar"]
+        ));
+        assert_eq!(read("incr_all\r\n").err(), Some(InputError::Invalid));
+        assert!(matches!(read("delete foo\r\n"), Ok(Command::Delete(key)) if key == "foo"));
+        assert!(matches!(read("delete foo 0\r\n"), Ok(Command::Delete(key)) if key == "foo"));
+        assert!(matches!(read("decr foo 2\r\n"), Ok(Command::Decr(key, 2)) if key == "foo"));
+        assert!(matches!(read("touch foo 10\r\n"), Ok(Command::Touch(key, 10)) if key == "foo"));
+        assert!(matches!(
+            read("touch foo 2592000\r\n"),
+            Ok(Command::Touch(_, 2_592_000))
+        ));
+        assert_eq!(
+            read("touch foo 2592001\r\n").err(),
+            Some(InputError::Invalid)
+        );
+        assert_eq!(
+            read("touch foo 18446744073709551615\r\n").err(),
+            Some(InputError::Invalid)
+        );
+        assert_eq!(read("touch foo\r\n").err(), Some(InputError::Invalid));
+        assert!(matches!(read("set foo 0 0 5\r\n"), Ok(Command::Store(5))));
+        assert!(matches!(read("cas foo 0 0 5 1\r\n"), Ok(Command::Store(5))));
+        assert!(matches!(read("stats\r\n"), Ok(Command::Stats)));
+        assert!(matches!(read("version\r\n"), Ok(Command::Version)));
+        assert!(matches!(read("quit\r\n"), Ok(Command::Quit)));
+        assert_eq!(read("version foo\r\n").err(), Some(InputError::Invalid));
+        assert_eq!(read("flush_all\r\n").err(), Some(InputError::Unknown));
+        assert_eq!(read("\r\n").err(), Some(InputError::Unknown));
+
+        assert!(matches!(
+            read("mg foo v t k Oabc s\r\n"),
+            Ok(Command::MetaGet(key, flags)) if key == "foo" && flags == MetaFlags {
+                value: true,
+                ttl: true,
+                key: true,
+                opaque: Some("abc".to_string()),
+                ..MetaFlags::default()
+            }
+        ));
+        assert!(matches!(
+            read("ma foo MD D5 q\r\n"),
+            Ok(Command::MetaArithmetic(key, flags)) if key == "foo" && flags == MetaFlags {
+                quiet: true,
+                delta: Some(5),
+                decrement: true,
+                ..MetaFlags::default()
+            }
+        ));
+        assert!(
+            matches!(read("md foo\r\n"), Ok(Command::MetaDelete(key, flags)) if key == "foo" && flags == MetaFlags::default())
+        );
+        assert!(matches!(read("mn\r\n"), Ok(Command::MetaNoop)));
+        assert_eq!(read("mg\r\n").err(), Some(InputError::Invalid));
+        assert_eq!(read("ma foo Dx\r\n").err(), Some(InputError::Invalid));
+        assert_eq!(read("ma foo Mx\r\n").err(), Some(InputError::Invalid));
+        assert_eq!(read("mg foo b\r\n").err(), Some(InputError::Invalid));
+        assert_eq!(read("mg foo 1\r\n").err(), Some(InputError::Invalid));
+        assert!(matches!(
+            read(&format!("mg foo O{}\r\n", "a".repeat(32))),
+            Ok(Command::MetaGet(_, flags)) if flags.opaque == Some("a".repeat(32))
+        ));
+        assert_eq!(
+            read(&format!("mg foo O{}\r\n", "a".repeat(33))).err(),
+            Some(InputError::OpaqueTooLong)
+        );
+
+        let noreply = |input: &str| read_input(input.as_bytes()).map(|(_, noreply)| noreply);
+        assert!(
+            matches!(read("incr foo 5 noreply\r\n"), Ok(Command::Incr(key, 5)) if key == "foo")
+        );
+        assert_eq!(noreply("incr foo 5 noreply\r\n"), Ok(true));
+        assert_eq!(noreply("incr foo noreply\r\n"), Ok(true));
+        assert_eq!(noreply("delete foo noreply\r\n"), Ok(true));
+        assert_eq!(noreply("set foo 0 0 5 noreply\r\n"), Ok(true));
+        assert_eq!(noreply("incr foo\r\n"), Ok(false));
+        assert!(matches!(read("incr noreply\r\n"), Ok(Command::Incr(key, 1)) if key == "noreply"));
+        assert_eq!(noreply("incr noreply\r\n"), Ok(false));
+        assert!(matches!(
+            read("incr_all foo noreply\r\n"),
+            Ok(Command::IncrAll(keys)) if keys == ["foo", "noreply"]
+        ));
+    }
+
+    #[async_std::test]
+    async fn test_base() {
+        let clock = ManualClock::new();
+
+        let rl = Arc::new(
+            ConcurrentRatelimit::with_clock(1, 1, Algorithm::SlidingLog, clock.clone()).unwrap(),
+        );
+        let xrl = Arc::new(ConcurrentCollection::with_clock(clock.clone()));
+        let mut handler = StreamHandler::new(&rl, &xrl);
+
+        let mut stream = MockTcpStream::from_rdata("incr zzz\r\n".to_string());
+
+        handler.handle_one(&mut stream).await.unwrap();
+
+        assert_eq!(stream.get_wdata(), "0\r\n");
+    }
+
+    #[async_std::test]
+    async fn test_short_writes() {
+        let clock = ManualClock::new();
+
+        let rl = Arc::new(
+            ConcurrentRatelimit::with_clock(1, 1, Algorithm::SlidingLog, clock.clone()).unwrap(),
+        );
+        let xrl = Arc::new(ConcurrentCollection::with_clock(clock.clone()));
+        let mut handler = StreamHandler::new(&rl, &xrl);
+
+        let mut stream =
+            MockTcpStream::from_rdata("incr_all foo bar baz\r\n".to_string()).with_max_write(1);
+        handler.handle_one(&mut stream).await.unwrap();
+        assert_eq!(stream.get_wdata(), "0\r\n");
+
+        let mut stream = MockTcpStream::from_rdata("version\r\n".to_string()).with_max_write(3);
+        handler.handle_one(&mut stream).await.unwrap();
+        assert_eq!(
+            stream.get_wdata(),
+            format!("VERSION {}\r\n", env!("CARGO_PKG_VERSION"))
+        );
+    }
+
+    #[async_std::test]
+    async fn test_incr_cost() {
+        let clock = ManualClock::new();
+
+        let rl = Arc::new(
+            ConcurrentRatelimit::with_clock(1, 1, Algorithm::SlidingLog, clock.clone()).unwrap(),
+        );
+        let xrl = Arc::new(ConcurrentCollection::with_clock(clock.clone()));
+        let stats = Arc::new(Stats::new());
+        let mut handler = StreamHandler::new(&rl, &xrl).with_stats(&stats);
+
+        // A cost exceeding the limit is refused whether the retry delay is replied or not,
+        // without counting a hit
+        let mut stream = MockTcpStream::from_rdata("incr 10/1_zzz 11\r\n".to_string());
+        handler.handle_one(&mut stream).await.unwrap();
+        assert_eq!(
+            stream.get_wdata(),
+            "CLIENT_ERROR Cost of 11 exceeds the limit of 10 hits\r\n"
+        );
+        assert_eq!(stats.hits_limited.load(Ordering::Relaxed), 0);
+
+        let mut retry = StreamHandler::new(&rl, &xrl)
+            .with_stats(&stats)
+            .with_retry_after(true);
+        retry.handle_one(&mut stream).await.unwrap();
+        assert_eq!(
+            stream.get_wdata(),
+            "CLIENT_ERROR Cost of 11 exceeds the limit of 10 hits\r\n"
+        );
+        assert_eq!(stats.hits_limited.load(Ordering::Relaxed), 0);
+
+        let mut stream = MockTcpStream::from_rdata("incr 10/1_zzz 10\r\n".to_string());
+        handler.handle_one(&mut stream).await.unwrap();
+        assert_eq!(stream.get_wdata(), "0\r\n");
+        assert_eq!(stats.hits_allowed.load(Ordering::Relaxed), 1);
+    }
+
+    #[async_std::test]
+    async fn test_retry_after() {
+        let clock = ManualClock::new();
+
+        let rl = Arc::new(
+            ConcurrentRatelimit::with_clock(1, 1, Algorithm::SlidingLog, clock.clone()).unwrap(),
+        );
+        let xrl = Arc::new(ConcurrentCollection::with_clock(clock.clone()));
+        let mut handler = StreamHandler::new(&rl, &xrl).with_retry_after(true);
+
+        let mut stream = MockTcpStream::from_rdata("incr 2/10_zzz\r\n".to_string());
+        handler.handle_one(&mut stream).await.unwrap();
+        assert_eq!(stream.get_wdata(), "0\r\n");
+
+        clock.advance(Duration::from_millis(2_500));
+        handler.handle_one(&mut stream).await.unwrap();
+        assert_eq!(stream.get_wdata(), "0\r\n");
+
+        handler.handle_one(&mut stream).await.unwrap();
+        assert_eq!(stream.get_wdata(), "7500\r\n");
+
+        clock.advance(Duration::from_millis(7_499));
+        handler.handle_one(&mut stream).await.unwrap();
+        assert_eq!(stream.get_wdata(), "1\r\n");
+
+        clock.advance(Duration::from_millis(1));
+        handler.handle_one(&mut stream).await.unwrap();
+        assert_eq!(stream.get_wdata(), "0\r\n");
+    }
+
+    #[async_std::test]
+    async fn test_retry_after_cost() {
+        let clock = ManualClock::new();
+
+        let rl = Arc::new(
+            ConcurrentRatelimit::with_clock(1, 1, Algorithm::SlidingLog, clock.clone()).unwrap(),
+        );
+        let xrl = Arc::new(ConcurrentCollection::with_clock(clock.clone()));
+        let mut handler = StreamHandler::new(&rl, &xrl).with_retry_after(true);
+
+        let mut stream = MockTcpStream::from_rdata("incr 3/10_zzz 2\r\n".to_string());
+        handler.handle_one(&mut stream).await.unwrap();
+        assert_eq!(stream.get_wdata(), "0\r\n");
+
+        clock.advance(Duration::from_millis(2_000));
+        let mut stream = MockTcpStream::from_rdata("incr 3/10_zzz\r\n".to_string());
+        handler.handle_one(&mut stream).await.unwrap();
+        assert_eq!(stream.get_wdata(), "0\r\n");
+
+        // two hits are only allowed once the two oldest ones expired
+        let mut stream = MockTcpStream::from_rdata("incr 3/10_zzz 2\r\n".to_string());
+        handler.handle_one(&mut stream).await.unwrap();
+        assert_eq!(stream.get_wdata(), "8000\r\n");
+
+        let mut stream = MockTcpStream::from_rdata("incr 3/10_zzz 4\r\n".to_string());
+        handler.handle_one(&mut stream).await.unwrap();
+        assert_eq!(
+            stream.get_wdata(),
+            "CLIENT_ERROR Cost of 4 exceeds the limit of 3 hits\r\n"
+        );
+    }
+
+    #[async_std::test]
+    async fn test_retry_after_fail_closed() {
+        let clock = ManualClock::new();
+
+        let rl = Arc::new(
+            ConcurrentRatelimit::with_clock(3, 1_000, Algorithm::SlidingLog, clock.clone())
+                .unwrap()
+                .with_bounds(Bounds::new(1).with_fail(FailMode::Closed)),
+        );
+        let xrl = Arc::new(ConcurrentCollection::with_clock(clock.clone()));
+        let mut handler = StreamHandler::new(&rl, &xrl).with_retry_after(true);
+
+        assert!(rl.hit("ip"));
+        let other = (0..1_000)
+            .map(|i| format!("ip{}", i))
+            .find(|name| !rl.check(name))
+            .unwrap();
+
+        // a new key is refused until the hits of the key holding its room expired
+        clock.advance(Duration::from_millis(400));
+        let mut stream = MockTcpStream::from_rdata(format!("incr {}\r\n", other));
+        handler.handle_one(&mut stream).await.unwrap();
+        assert_eq!(stream.get_wdata(), "600\r\n");
+
+        clock.advance(Duration::from_millis(600));
+        handler.handle_one(&mut stream).await.unwrap();
+        assert_eq!(stream.get_wdata(), "0\r\n");
+    }
+
+    #[async_std::test]
+    async fn test_incr_all() {
+        let clock = ManualClock::new();
+
+        let rl = Arc::new(
+            ConcurrentRatelimit::with_clock(3, 1_000, Algorithm::SlidingLog, clock.clone())
+                .unwrap(),
+        );
+        let xrl = Arc::new(ConcurrentCollection::with_clock(clock.clone()));
+        let mut handler = StreamHandler::new(&rl, &xrl);
+
+        let mut stream = MockTcpStream::from_rdata("incr_all ip 2/10_user\r\n".to_string());
+        for _ in 0..2 {
+            handler.handle_one(&mut stream).await.unwrap();
+            assert_eq!(stream.get_wdata(), "0\r\n");
+        }
+
+        // user is limited, ip is not charged
+        handler.handle_one(&mut stream).await.unwrap();
+        assert_eq!(stream.get_wdata(), "1\r\n");
+        assert!(rl.check_n("ip", 1));
+
+        // ip is present twice
+        let mut stream = MockTcpStream::from_rdata("incr_all ip 2/10_other ip\r\n".to_string());
+        handler.handle_one(&mut stream).await.unwrap();
+        assert_eq!(stream.get_wdata(), "1\r\n");
+        assert!(xrl
+            .lock("other")
+            .get_instance(2, 10_000)
+            .unwrap()
+            .check_n("other", 2));
+
+        // Each key has room on its own, not both: the first one is not recorded either
+        let rl = Arc::new(
+            ConcurrentRatelimit::with_clock(3, 1_000, Algorithm::SlidingLog, clock.clone())
+                .unwrap()
+                .with_bounds(Bounds::new(1).with_fail(FailMode::Closed)),
+        );
+        let mut handler = StreamHandler::new(&rl, &xrl);
+        assert!(rl.hit("ip"));
+        let other = (0..1_000)
+            .map(|i| format!("ip{}", i))
+            .find(|name| !rl.check(name))
+            .unwrap();
+        assert!(rl.reset("ip"));
+
+        let mut stream = MockTcpStream::from_rdata(format!("incr_all ip {}\r\n", other));
+        handler.handle_one(&mut stream).await.unwrap();
+        assert_eq!(stream.get_wdata(), "1\r\n");
+        assert!(rl.is_empty());
+    }
+
+    #[async_std::test]
+    async fn test_delete() {
+        let clock = ManualClock::new();
+
+        let rl = Arc::new(
+            ConcurrentRatelimit::with_clock(1, 1_000, Algorithm::SlidingLog, clock.clone())
+                .unwrap(),
+        );
+        let xrl = Arc::new(ConcurrentCollection::with_clock(clock.clone()));
+        let mut handler = StreamHandler::new(&rl, &xrl);
+
+        for keyname in ["zzz", "1/10_zzz"] {
+            let mut incr = MockTcpStream::from_rdata(format!("incr {}\r\n", keyname));
+            let mut delete = MockTcpStream::from_rdata(format!("delete {}\r\n", keyname));
+
+            handler.handle_one(&mut delete).await.unwrap();
+            assert_eq!(delete.get_wdata(), "NOT_FOUND\r\n");
+
+            handler.handle_one(&mut incr).await.unwrap();
+            assert_eq!(incr.get_wdata(), "0\r\n");
+            handler.handle_one(&mut incr).await.unwrap();
+            assert_eq!(incr.get_wdata(), "1\r\n");
+
+            handler.handle_one(&mut delete).await.unwrap();
+            assert_eq!(delete.get_wdata(), "DELETED\r\n");
+
+            handler.handle_one(&mut incr).await.unwrap();
+            assert_eq!(incr.get_wdata(), "0\r\n");
+        }
+    }
+
+    #[async_std::test]
+    async fn test_get() {
+        let clock = ManualClock::new();
+
+        let rl = Arc::new(
+            ConcurrentRatelimit::with_clock(1, 1_000, Algorithm::SlidingLog, clock.clone())
+                .unwrap(),
+        );
+        let xrl = Arc::new(ConcurrentCollection::with_clock(clock.clone()));
+        let mut handler = StreamHandler::new(&rl, &xrl);
+
+        for _ in 0..2 {
+            let mut stream = MockTcpStream::from_rdata("get zzz\r\n".to_string());
+            handler.handle_one(&mut stream).await.unwrap();
+            assert_eq!(stream.get_wdata(), "VALUE zzz 0 1\r\n1\r\nEND\r\n");
+        }
+
+        let mut stream = MockTcpStream::from_rdata("incr zzz\r\n".to_string());
+        handler.handle_one(&mut stream).await.unwrap();
+
+        let mut stream = MockTcpStream::from_rdata("get zzz 10/1_zzz\r\n".to_string());
+        handler.handle_one(&mut stream).await.unwrap();
+        assert_eq!(
+            stream.get_wdata(),
+            "VALUE zzz 0 1\r\n0\r\nVALUE 10/1_zzz 0 2\r\n10\r\nEND\r\n"
+        );
+    }
+
+    #[async_std::test]
+    async fn test_dynamic_limits_disabled() {
+        let clock = ManualClock::new();
+
+        let rl = Arc::new(
+            ConcurrentRatelimit::with_clock(1, 1_000, Algorithm::SlidingLog, clock.clone())
+                .unwrap(),
+        );
+        let xrl = Arc::new(ConcurrentCollection::with_clock(clock.clone()));
+        let mut handler = StreamHandler::new(&rl, &xrl).with_dynamic_limits(false);
+
+        let mut stream = MockTcpStream::from_rdata("incr 10/1_zzz\r\n".to_string());
+        handler.handle_one(&mut stream).await.unwrap();
+        assert_eq!(stream.get_wdata(), "0\r\n");
+        handler.handle_one(&mut stream).await.unwrap();
+        assert_eq!(stream.get_wdata(), "1\r\n");
+
+        assert!(!rl.check("10/1_zzz"));
+        assert!(xrl.is_empty());
+    }
+
+    #[async_std::test]
+    async fn test_rules() {
+        let clock = ManualClock::new();
+
+        let rl = Arc::new(
+            ConcurrentRatelimit::with_clock(1, 1_000, Algorithm::SlidingLog, clock.clone())
+                .unwrap(),
+        );
+        let xrl = Arc::new(ConcurrentCollection::with_clock(clock.clone()));
+        let login =
+            ConcurrentRatelimit::with_clock(2, 60_000, Algorithm::SlidingLog, clock.clone())
+                .unwrap();
+        let rules = Arc::new(Rules::default().with_rule(Rule::new(
+            "login",
+            KeyPattern::Regex(Regex::new("^login:").unwrap()),
+            vec![login],
+        )));
+        let mut handler = StreamHandler::new(&rl, &xrl).with_rules(&rules);
+
+        let mut stream = MockTcpStream::from_rdata("incr login:foo\r\n".to_string());
+        for _ in 0..2 {
+            handler.handle_one(&mut stream).await.unwrap();
+            assert_eq!(stream.get_wdata(), "0\r\n");
+        }
+        handler.handle_one(&mut stream).await.unwrap();
+        assert_eq!(stream.get_wdata(), "1\r\n");
+        assert!(rl.is_empty());
+
+        // Still limited by the rule once the default limit would allow it
+        clock.advance(Duration::from_secs(2));
+        handler.handle_one(&mut stream).await.unwrap();
+        assert_eq!(stream.get_wdata(), "1\r\n");
+
+        // A specified limit takes precedence over the rules
+        let mut stream = MockTcpStream::from_rdata("incr 5/1_login:foo\r\n".to_string());
+        handler.handle_one(&mut stream).await.unwrap();
+        assert_eq!(stream.get_wdata(), "0\r\n");
+
+        let mut stream = MockTcpStream::from_rdata("incr_all login:bar bar\r\n".to_string());
+        handler.handle_one(&mut stream).await.unwrap();
+        assert_eq!(stream.get_wdata(), "0\r\n");
+        assert!(!rl.check("bar"));
+        assert!(rules.find("login:bar").unwrap().limits()[0].check("login:bar"));
+
+        let mut stream = MockTcpStream::from_rdata("delete login:foo\r\n".to_string());
+        handler.handle_one(&mut stream).await.unwrap();
+        assert_eq!(stream.get_wdata(), "DELETED\r\n");
+    }
+
+    #[async_std::test]
+    async fn test_stacked_limits() {
+        let clock = ManualClock::new();
+
+        let rl = Arc::new(
+            ConcurrentRatelimit::with_clock(10, 1_000, Algorithm::SlidingLog, clock.clone())
+                .unwrap(),
+        );
+        let xrl = Arc::new(ConcurrentCollection::with_clock(clock.clone()));
+        let mut handler = StreamHandler::new(&rl, &xrl).with_retry_after(true);
+
+        let mut stream = MockTcpStream::from_rdata("incr 2/1,3/60_foo\r\n".to_string());
+        for _ in 0..2 {
+            handler.handle_one(&mut stream).await.unwrap();
+            assert_eq!(stream.get_wdata(), "0\r\n");
+        }
+        handler.handle_one(&mut stream).await.unwrap();
+        assert_eq!(stream.get_wdata(), "1000\r\n");
+
+        // Allowed by the burst limit, refused by the sustained one
+        clock.advance(Duration::from_secs(1));
+        handler.handle_one(&mut stream).await.unwrap();
+        assert_eq!(stream.get_wdata(), "0\r\n");
+        handler.handle_one(&mut stream).await.unwrap();
+        assert_eq!(stream.get_wdata(), "59000\r\n");
+
+        // The refused hit was not recorded by the burst limit
+        clock.advance(Duration::from_secs(1));
+        let mut stream = MockTcpStream::from_rdata("get 2/1_foo\r\n".to_string());
+        handler.handle_one(&mut stream).await.unwrap();
+        assert_eq!(stream.get_wdata(), "VALUE 2/1_foo 0 1\r\n2\r\nEND\r\n");
+
+        let mut stream = MockTcpStream::from_rdata("incr_all 2/1,3/60_foo bar\r\n".to_string());
+        handler.handle_one(&mut stream).await.unwrap();
+        assert_eq!(stream.get_wdata(), "1\r\n");
+        assert!(rl.check_n("bar", 10));
+
+        let mut stream = MockTcpStream::from_rdata("delete 2/1,3/60_foo\r\n".to_string());
+        handler.handle_one(&mut stream).await.unwrap();
+        assert_eq!(stream.get_wdata(), "DELETED\r\n");
+        let mut stream = MockTcpStream::from_rdata("incr 2/1,3/60_foo 2\r\n".to_string());
+        handler.handle_one(&mut stream).await.unwrap();
+        assert_eq!(stream.get_wdata(), "0\r\n");
+    }
+
+    #[async_std::test]
+    async fn test_pipelining() {
+        let clock = ManualClock::new();
+
+        let rl = Arc::new(
+            ConcurrentRatelimit::with_clock(1, 1_000, Algorithm::SlidingLog, clock.clone())
+                .unwrap(),
+        );
+        let xrl = Arc::new(ConcurrentCollection::with_clock(clock.clone()));
+        let mut handler = StreamHandler::new(&rl, &xrl);
+
+        // Several commands in one read, answered in order
+        let mut stream = MockTcpStream::from_chunks(&["incr a\r\nincr b\r\nincr a\r\n"]);
+        handler.handle_one(&mut stream).await.unwrap();
+        assert_eq!(stream.get_wdata(), "0\r\n0\r\n1\r\n");
+
+        // A command split across reads, and commands terminated by "\n" only
+        let mut stream =
+            MockTcpStream::from_chunks(&["incr", " c\r", "\nget c\r\nincr d\n", "incr d"]);
+        handler.handle_one(&mut stream).await.unwrap();
+        assert_eq!(stream.get_wdata(), "");
+        handler.handle_one(&mut stream).await.unwrap();
+        assert_eq!(stream.get_wdata(), "");
+        handler.handle_one(&mut stream).await.unwrap();
+        assert_eq!(stream.get_wdata(), "0\r\nVALUE c 0 1\r\n0\r\nEND\r\n0\r\n");
+        handler.handle_one(&mut stream).await.unwrap();
+        assert_eq!(stream.get_wdata(), "");
+        assert!(handler.handle_one(&mut stream).await.is_err());
+    }
+
+    #[async_std::test]
+    async fn test_long_lines() {
+        let clock = ManualClock::new();
+
+        let rl = Arc::new(
+            ConcurrentRatelimit::with_clock(1, 1_000, Algorithm::SlidingLog, clock.clone())
+                .unwrap(),
+        );
+        let xrl = Arc::new(ConcurrentCollection::with_clock(clock.clone()));
+        let mut handler = StreamHandler::new(&rl, &xrl);
+
+        // A key longer than a read
+        let key = "k".repeat(READ_SIZE);
+        let input = format!("incr {}\r\nincr {}\r\n", key, key);
+        let mut stream = MockTcpStream::from_chunks(&[&input]);
+        handler.handle_one(&mut stream).await.unwrap();
+        handler.handle_one(&mut stream).await.unwrap();
+        handler.handle_one(&mut stream).await.unwrap();
+        assert_eq!(stream.get_wdata(), "0\r\n1\r\n");
+
+        // A line too long is refused, the following ones are handled
+        let input = format!("incr {}\r\nincr foo\r\n", "k".repeat(MAX_LINE * 2));
+        let mut stream = MockTcpStream::from_chunks(&[&input]);
+        while handler.handle_one(&mut stream).await.is_ok() {}
+        assert_eq!(stream.get_wdata(), "CLIENT_ERROR line too long\r\n0\r\n");
+    }
+
+    #[async_std::test]
+    async fn test_decr() {
+        let clock = ManualClock::new();
+
+        let rl = Arc::new(
+            ConcurrentRatelimit::with_clock(3, 1_000, Algorithm::SlidingLog, clock.clone())
+                .unwrap(),
+        );
+        let xrl = Arc::new(ConcurrentCollection::with_clock(clock.clone()));
+        let mut handler = StreamHandler::new(&rl, &xrl);
+
+        for keyname in ["zzz", "3/10_zzz"] {
+            let mut stream = MockTcpStream::from_chunks(&[
+                &format!("decr {}\r\n", keyname),
+                &format!("incr {} 3\r\nincr {}\r\n", keyname, keyname),
+                &format!("decr {} 2\r\nincr {}\r\n", keyname, keyname),
+            ]);
+            handler.handle_one(&mut stream).await.unwrap();
+            assert_eq!(stream.get_wdata(), "NOT_FOUND\r\n");
+            handler.handle_one(&mut stream).await.unwrap();
+            assert_eq!(stream.get_wdata(), "0\r\n1\r\n");
+            handler.handle_one(&mut stream).await.unwrap();
+            assert_eq!(stream.get_wdata(), "2\r\n0\r\n");
+        }
+    }
+
+    #[async_std::test]
+    async fn test_touch() {
+        let clock = ManualClock::new();
+
+        let rl = Arc::new(
+            ConcurrentRatelimit::with_clock(3, 1_000, Algorithm::SlidingLog, clock.clone())
+                .unwrap(),
+        );
+        let xrl = Arc::new(ConcurrentCollection::with_clock(clock.clone()));
+        let mut handler = StreamHandler::new(&rl, &xrl).with_retry_after(true);
+
+        let mut stream = MockTcpStream::from_chunks(&[
+            "touch zzz 60\r\n",
+            "incr zzz\r\ntouch zzz 60\r\nincr zzz\r\nget zzz\r\n",
+        ]);
+        handler.handle_one(&mut stream).await.unwrap();
+        assert_eq!(stream.get_wdata(), "NOT_FOUND\r\n");
+        handler.handle_one(&mut stream).await.unwrap();
+        assert_eq!(
+            stream.get_wdata(),
+            "0\r\nTOUCHED\r\n60000\r\nVALUE zzz 0 1\r\n0\r\nEND\r\n"
+        );
+
+        clock.advance(Duration::from_secs(60));
+        let mut stream = MockTcpStream::from_rdata("incr zzz\r\n".to_string());
+        handler.handle_one(&mut stream).await.unwrap();
+        assert_eq!(stream.get_wdata(), "0\r\n");
+    }
+
+    #[async_std::test]
+    async fn test_protocol() {
+        let clock = ManualClock::new();
+
+        let rl = Arc::new(
+            ConcurrentRatelimit::with_clock(1, 1_000, Algorithm::SlidingLog, clock.clone())
+                .unwrap(),
+        );
+        let xrl = Arc::new(ConcurrentCollection::with_clock(clock.clone()).with_max_limits(1));
+        let stats = Arc::new(Stats::new());
+        let mut handler = StreamHandler::new(&rl, &xrl).with_stats(&stats);
+
+        let mut stream = MockTcpStream::from_rdata(
+            "version\r\nflush_all\r\nincr\r\nincr 0/1_foo\r\nincr 1/1_foo\r\nincr 2/1_foo\r\n"
+                .to_string(),
+        );
+        handler.handle_one(&mut stream).await.unwrap();
+        assert_eq!(
+            stream.get_wdata(),
+            format!(
+                "VERSION {}\r\nERROR\r\nCLIENT_ERROR bad command line format\r\n\
+                 CLIENT_ERROR Invalid ratelimit specification, hits must be greater than 0\r\n\
+                 0\r\n\
+                 SERVER_ERROR Too many distinct limits, the maximum is 1\r\n",
+                env!("CARGO_PKG_VERSION")
+            )
+        );
+
+        // The data block of a storage command is skipped, even split across reads
+        let mut stream = MockTcpStream::from_chunks(&[
+            "set foo 0 0 18\r\nincr foo",
+            "\r\nincr foo\r\nincr foo\r\n",
+        ]);
+        handler.handle_one(&mut stream).await.unwrap();
+        handler.handle_one(&mut stream).await.unwrap();
+        assert_eq!(stream.get_wdata(), "NOT_STORED\r\n0\r\n");
+
+        // Data blocks too large are refused, and still skipped
+        let data = "incr foo\r\n".repeat(MAX_DATA / 10 + 1);
+        let mut stream = MockTcpStream::from_chunks(&[
+            &format!("set foo 0 0 {}\r\n", data.len()),
+            &data,
+            "\r\nversion\r\n",
+        ]);
+        handler.handle_one(&mut stream).await.unwrap();
+        assert_eq!(
+            stream.get_wdata(),
+            "SERVER_ERROR object too large for cache\r\n"
+        );
+        while handler.data > 0 {
+            handler.handle_one(&mut stream).await.unwrap();
+        }
+        assert_eq!(
+            stream.get_wdata(),
+            format!("VERSION {}\r\n", env!("CARGO_PKG_VERSION"))
+        );
+
+        // Unless the end of the block cannot be found, the connection is then closed
+        let mut stream = MockTcpStream::from_rdata(
+            "set foo 0 0 18446744073709551615\r\nincr foo\r\n".to_string(),
+        );
+        let mut closed = StreamHandler::new(&rl, &xrl).with_stats(&stats);
+        assert!(closed.handle_one(&mut stream).await.is_err());
+        assert_eq!(
+            stream.get_wdata(),
+            "SERVER_ERROR object too large for cache\r\n"
+        );
+
+        let mut stream = MockTcpStream::from_rdata("stats\r\n".to_string());
+        handler.handle_one(&mut stream).await.unwrap();
+        let response = stream.get_wdata();
+        assert!(response.contains("STAT cmd_incr 4\r\n"));
+        assert!(response.contains("STAT hits_allowed 2\r\n"));
+        assert!(response.contains("STAT curr_items 2\r\n"));
+        assert!(response.ends_with("END\r\n"));
+
+        // Pipelined commands following "quit" are ignored
+        let mut stream = MockTcpStream::from_chunks(&["incr bar\r\nquit\r\nincr bar\r\n"]);
+        handler.main(&mut stream).await;
+        assert_eq!(stream.get_wdata(), "0\r\n");
+        assert_eq!(stats.total_connections.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.curr_connections.load(Ordering::Relaxed), 0);
+    }
+
+    #[async_std::test]
+    async fn test_noreply() {
+        let clock = ManualClock::new();
+
+        let rl = Arc::new(
+            ConcurrentRatelimit::with_clock(2, 1_000, Algorithm::SlidingLog, clock.clone())
+                .unwrap(),
+        );
+        let xrl = Arc::new(ConcurrentCollection::with_clock(clock.clone()));
+        let mut handler = StreamHandler::new(&rl, &xrl);
+
+        let mut stream = MockTcpStream::from_chunks(&[
+            "incr foo noreply\r\nincr 5/1_foo 5 noreply\r\nincr foo noreply\r\nincr foo noreply\r\n",
+            "get foo 5/1_foo\r\ndelete foo noreply\r\nget foo\r\n",
+            "set foo 0 0 3 noreply\r\nbar\r\nincr 0/1_foo noreply\r\nfoo noreply\r\n",
+        ]);
+        handler.handle_one(&mut stream).await.unwrap();
+        assert_eq!(stream.get_wdata(), "");
+        assert!(!rl.check("foo"));
+
+        handler.handle_one(&mut stream).await.unwrap();
+        assert_eq!(
+            stream.get_wdata(),
+            "VALUE foo 0 1\r\n0\r\nVALUE 5/1_foo 0 1\r\n0\r\nEND\r\nVALUE foo 0 1\r\n2\r\nEND\r\n"
+        );
+
+        // Errors of the limits are not replied either, unlike the errors of the command line
+        handler.handle_one(&mut stream).await.unwrap();
+        assert_eq!(stream.get_wdata(), "ERROR\r\n");
+    }
+
+    #[async_std::test]
+    async fn test_meta() {
+        let clock = ManualClock::new();
+
+        let rl = Arc::new(
+            ConcurrentRatelimit::with_clock(2, 10_000, Algorithm::SlidingLog, clock.clone())
+                .unwrap(),
+        );
+        let xrl = Arc::new(ConcurrentCollection::with_clock(clock.clone()));
+        let mut handler = StreamHandler::new(&rl, &xrl).with_retry_after(true);
+
+        let mut stream = MockTcpStream::from_chunks(&[
+            "mg foo v t k O1\r\nma foo\r\nma foo v t O2\r\n",
+            "ma foo v q O3\r\nma foo q O4\r\nmg foo O5\r\nmn\r\n",
+            "ma foo MD D2 v\r\nma bar MD O6\r\nma 5/1_foo D5 v\r\n",
+            "md foo q\r\nmd foo O7\r\nmd foo q\r\nmn\r\n",
+        ]);
+
+        handler.handle_one(&mut stream).await.unwrap();
+        assert_eq!(
+            stream.get_wdata(),
+            "VA 1 t0 kfoo O1\r\n2\r\nHD\r\nVA 1 t10 O2\r\n0\r\n"
+        );
+
+        // Quiet commands only skip the replies of successes, refused hits are replied
+        clock.advance(Duration::from_secs(1));
+        handler.handle_one(&mut stream).await.unwrap();
+        assert_eq!(
+            stream.get_wdata(),
+            "VA 4 O3\r\n9000\r\nNS O4\r\nHD O5\r\nMN\r\n"
+        );
+
+        handler.handle_one(&mut stream).await.unwrap();
+        assert_eq!(stream.get_wdata(), "VA 1\r\n2\r\nNF O6\r\nVA 1\r\n0\r\n");
+
+        handler.handle_one(&mut stream).await.unwrap();
+        assert_eq!(stream.get_wdata(), "NF O7\r\nNF\r\nMN\r\n");
+
+        let mut stream = MockTcpStream::from_rdata(format!("mg foo v O{}\r\n", "a".repeat(33)));
+        handler.handle_one(&mut stream).await.unwrap();
+        assert_eq!(stream.get_wdata(), "CLIENT_ERROR opaque token too long\r\n");
     }
 }
@@ -0,0 +1,525 @@
+use std::str;
+
+use async_std::prelude::*;
+
+use crate::handlers::memcache::{AsyncWriter, Next, StreamHandler, MAX_COST};
+use crate::{Clock, CollectionError};
+
+/// First byte of the requests, telling the binary protocol from the text one
+pub(crate) const MAGIC_REQUEST: u8 = 0x80;
+/// First byte of the responses
+const MAGIC_RESPONSE: u8 = 0x81;
+/// Size of the header of the requests and responses
+pub(crate) const HEADER_SIZE: usize = 24;
+
+/// Commands handled, the quiet ones only reply on failure, or on success for gets
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Opcode {
+    Get,
+    GetK,
+    Increment,
+    Decrement,
+    Delete,
+    Noop,
+    Version,
+    Stat,
+    Quit,
+}
+
+impl Opcode {
+    /// The command and whether it is quiet
+    fn from_u8(opcode: u8) -> Option<(Opcode, bool)> {
+        match opcode {
+            0x00 => Some((Opcode::Get, false)),
+            0x09 => Some((Opcode::Get, true)),
+            0x0c => Some((Opcode::GetK, false)),
+            0x0d => Some((Opcode::GetK, true)),
+            0x05 => Some((Opcode::Increment, false)),
+            0x15 => Some((Opcode::Increment, true)),
+            0x06 => Some((Opcode::Decrement, false)),
+            0x16 => Some((Opcode::Decrement, true)),
+            0x04 => Some((Opcode::Delete, false)),
+            0x14 => Some((Opcode::Delete, true)),
+            0x0a => Some((Opcode::Noop, false)),
+            0x0b => Some((Opcode::Version, false)),
+            0x10 => Some((Opcode::Stat, false)),
+            0x07 => Some((Opcode::Quit, false)),
+            0x17 => Some((Opcode::Quit, true)),
+            _ => None,
+        }
+    }
+}
+
+/// Status of a response
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Status {
+    NoError = 0x0000,
+    KeyNotFound = 0x0001,
+    ValueTooLarge = 0x0003,
+    InvalidArguments = 0x0004,
+    UnknownCommand = 0x0081,
+    OutOfMemory = 0x0082,
+}
+
+impl Status {
+    /// An error response with the default message of the status
+    fn error(self, header: &Header) -> Vec<u8> {
+        let message = match self {
+            Status::NoError => "",
+            Status::KeyNotFound => "Not found",
+            Status::ValueTooLarge => "Too large",
+            Status::InvalidArguments => "Invalid arguments",
+            Status::UnknownCommand => "Unknown command",
+            Status::OutOfMemory => "Out of memory",
+        };
+        error_response(header, self, message)
+    }
+}
+
+impl From<&CollectionError> for Status {
+    fn from(error: &CollectionError) -> Status {
+        match error {
+            CollectionError::TooManyLimits { .. } => Status::OutOfMemory,
+            CollectionError::Invalid(_)
+            | CollectionError::NotAllowed { .. }
+            | CollectionError::CostExceedsLimit { .. } => Status::InvalidArguments,
+        }
+    }
+}
+
+/// Header of a request, the fields not used by the server being ignored
+pub(crate) struct Header {
+    opcode: u8,
+    key_length: usize,
+    extras_length: usize,
+    body_length: usize,
+    opaque: u32,
+}
+
+impl Header {
+    /// Parses the header at the beginning of `input`, if it is a request
+    pub(crate) fn parse(input: &[u8]) -> Option<Header> {
+        if input.len() < HEADER_SIZE || input[0] != MAGIC_REQUEST {
+            return None;
+        }
+
+        let be_u32 = |offset: usize| {
+            u32::from_be_bytes([
+                input[offset],
+                input[offset + 1],
+                input[offset + 2],
+                input[offset + 3],
+            ])
+        };
+
+        Some(Header {
+            opcode: input[1],
+            key_length: usize::from(u16::from_be_bytes([input[2], input[3]])),
+            extras_length: usize::from(input[4]),
+            body_length: usize::try_from(be_u32(8)).ok()?,
+            opaque: be_u32(12),
+        })
+    }
+
+    /// Size of the extras, key and value following the header
+    pub(crate) fn body_length(&self) -> usize {
+        self.body_length
+    }
+}
+
+/// A response to the request of `header`, echoing its opcode and opaque
+fn response(header: &Header, status: Status, extras: &[u8], key: &[u8], value: &[u8]) -> Vec<u8> {
+    let body_length = extras.len() + key.len() + value.len();
+
+    let mut response = Vec::with_capacity(HEADER_SIZE + body_length);
+    response.push(MAGIC_RESPONSE);
+    response.push(header.opcode);
+    response.extend_from_slice(&u16::try_from(key.len()).unwrap().to_be_bytes());
+    response.push(u8::try_from(extras.len()).unwrap());
+    // Data type
+    response.push(0);
+    response.extend_from_slice(&(status as u16).to_be_bytes());
+    response.extend_from_slice(&u32::try_from(body_length).unwrap().to_be_bytes());
+    response.extend_from_slice(&header.opaque.to_be_bytes());
+    // CAS
+    response.extend_from_slice(&0u64.to_be_bytes());
+    response.extend_from_slice(extras);
+    response.extend_from_slice(key);
+    response.extend_from_slice(value);
+    response
+}
+
+/// An error response, with its message as value
+fn error_response(header: &Header, status: Status, message: &str) -> Vec<u8> {
+    response(header, status, &[], &[], message.as_bytes())
+}
+
+async fn write(response: &[u8], stream: &mut impl AsyncWriter) -> bool {
+    stream.write_all(response).await.is_ok() && stream.flush().await.is_ok()
+}
+
+/// Replies to a request whose body is too large to be handled, the body being skipped
+pub(crate) async fn reply_too_large(header: &Header, stream: &mut impl AsyncWriter) -> bool {
+    write(&Status::ValueTooLarge.error(header), stream).await
+}
+
+/// Handles a request of the binary protocol, `body` being its extras, key and value
+///
+/// The commands have the same semantics as in the text protocol:
+/// an increment replies 0 when the hit is allowed, a get replies the hits remaining…
+pub(crate) async fn handle_request<C: Clock>(
+    handler: &StreamHandler<C>,
+    header: &Header,
+    body: &[u8],
+    stream: &mut impl AsyncWriter,
+) -> Next {
+    let (opcode, quiet) = match Opcode::from_u8(header.opcode) {
+        Some(opcode) => opcode,
+        None => {
+            write(&Status::UnknownCommand.error(header), stream).await;
+            return Next::Line;
+        }
+    };
+
+    let responses = match parse_body(header, body) {
+        Some((extras, key)) => handle_command(handler, header, opcode, extras, key),
+        None => Err(Status::InvalidArguments.error(header)),
+    };
+
+    let responses = match responses {
+        // Quiet commands only reply on failure, quiet gets on misses only
+        Ok(_) if quiet && !matches!(opcode, Opcode::Get | Opcode::GetK) => vec![],
+        Ok(responses) => responses,
+        Err(error) => vec![error],
+    };
+    write(&responses.concat(), stream).await;
+
+    match opcode {
+        Opcode::Quit => Next::Quit,
+        _ => Next::Line,
+    }
+}
+
+/// Splits the body of a request into its extras and key, its value being ignored
+fn parse_body<'a>(header: &Header, body: &'a [u8]) -> Option<(&'a [u8], &'a str)> {
+    let (extras, rest) = body.split_at_checked(header.extras_length)?;
+    let (key, _) = rest.split_at_checked(header.key_length)?;
+
+    Some((extras, str::from_utf8(key).ok()?))
+}
+
+/// Runs a command, returning its responses or the error response of its failure
+fn handle_command<C: Clock>(
+    handler: &StreamHandler<C>,
+    header: &Header,
+    opcode: Opcode,
+    extras: &[u8],
+    key: &str,
+) -> Result<Vec<Vec<u8>>, Vec<u8>> {
+    let needs_key = matches!(
+        opcode,
+        Opcode::Get | Opcode::GetK | Opcode::Increment | Opcode::Decrement | Opcode::Delete
+    );
+    if needs_key && key.is_empty() {
+        return Err(Status::InvalidArguments.error(header));
+    }
+
+    let ok = |extras: &[u8], key: &[u8], value: &[u8]| {
+        Ok(vec![response(header, Status::NoError, extras, key, value)])
+    };
+    let failed =
+        |error: CollectionError| error_response(header, Status::from(&error), &error.to_string());
+
+    match opcode {
+        Opcode::Get | Opcode::GetK => {
+            let remaining = handler.check(key).map_err(failed)?.remaining;
+            let key = match opcode {
+                Opcode::GetK => key.as_bytes(),
+                _ => &[],
+            };
+            // Flags, then the value
+            ok(&[0; 4], key, remaining.to_string().as_bytes())
+        }
+        Opcode::Increment | Opcode::Decrement => {
+            // Delta, initial value and expiration, only the delta being used, as the cost
+            if extras.len() != 20 {
+                return Err(Status::InvalidArguments.error(header));
+            }
+            let delta = u64::from_be_bytes(extras[..8].try_into().unwrap());
+            let cost = u32::try_from(delta)
+                .ok()
+                .filter(|cost| (1..=MAX_COST).contains(cost))
+                .ok_or_else(|| Status::InvalidArguments.error(header))?;
+
+            let value = match opcode {
+                Opcode::Increment => handler.incr(key, cost).map(Some),
+                _ => handler
+                    .decr(key, cost)
+                    .map(|decision| decision.map(|decision| u64::from(decision.remaining))),
+            };
+            match value.map_err(failed)? {
+                Some(value) => ok(&[], &[], &value.to_be_bytes()),
+                None => Err(Status::KeyNotFound.error(header)),
+            }
+        }
+        Opcode::Delete => match handler.delete(key) {
+            true => ok(&[], &[], &[]),
+            false => Err(Status::KeyNotFound.error(header)),
+        },
+        Opcode::Noop | Opcode::Quit => ok(&[], &[], &[]),
+        Opcode::Version => ok(&[], &[], env!("CARGO_PKG_VERSION").as_bytes()),
+        Opcode::Stat => {
+            // Only the general statistics
+            if !key.is_empty() {
+                return Err(Status::KeyNotFound.error(header));
+            }
+
+            let mut responses: Vec<_> = handler
+                .stats()
+                .iter()
+                .map(|(name, value)| {
+                    response(
+                        header,
+                        Status::NoError,
+                        &[],
+                        name.as_bytes(),
+                        value.as_bytes(),
+                    )
+                })
+                .collect();
+            // Terminated by an empty statistic
+            responses.push(response(header, Status::NoError, &[], &[], &[]));
+            Ok(responses)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::time::Duration;
+
+    use async_std::sync::Arc;
+
+    use crate::testing::MockTcpStream;
+    use crate::{Algorithm, ConcurrentCollection, ConcurrentRatelimit, ManualClock};
+
+    /// A request, its opaque being its opcode
+    fn request(opcode: u8, extras: &[u8], key: &str) -> Vec<u8> {
+        let mut request = vec![MAGIC_REQUEST, opcode];
+        request.extend_from_slice(&u16::try_from(key.len()).unwrap().to_be_bytes());
+        request.extend_from_slice(&[u8::try_from(extras.len()).unwrap(), 0, 0, 0]);
+        let length = u32::try_from(extras.len() + key.len()).unwrap();
+        request.extend_from_slice(&length.to_be_bytes());
+        request.extend_from_slice(&[0, 0, 0, opcode]);
+        request.extend_from_slice(&[0; 8]);
+        request.extend_from_slice(extras);
+        request.extend_from_slice(key.as_bytes());
+        request
+    }
+
+    /// Extras of an increment or decrement
+    fn delta(delta: u64) -> Vec<u8> {
+        let mut extras = delta.to_be_bytes().to_vec();
+        extras.extend_from_slice(&[0; 12]);
+        extras
+    }
+
+    /// The responses as `(opcode, status, key, value)`, checking their header
+    fn responses(mut data: &[u8]) -> Vec<(u8, u16, String, Vec<u8>)> {
+        let mut responses = vec![];
+        while !data.is_empty() {
+            assert_eq!(data[0], MAGIC_RESPONSE);
+            let key_length = usize::from(u16::from_be_bytes([data[2], data[3]]));
+            let extras_length = usize::from(data[4]);
+            let length =
+                usize::try_from(u32::from_be_bytes(data[8..12].try_into().unwrap())).unwrap();
+            // The opaque of the request
+            assert_eq!(data[15], data[1]);
+
+            let body = &data[HEADER_SIZE..HEADER_SIZE + length];
+            let key = str::from_utf8(&body[extras_length..extras_length + key_length]).unwrap();
+            responses.push((
+                data[1],
+                u16::from_be_bytes([data[6], data[7]]),
+                key.to_string(),
+                body[extras_length + key_length..].to_vec(),
+            ));
+            data = &data[HEADER_SIZE + length..];
+        }
+        responses
+    }
+
+    fn handler(clock: &ManualClock) -> StreamHandler<ManualClock> {
+        let rl = Arc::new(
+            ConcurrentRatelimit::with_clock(2, 1_000, Algorithm::SlidingLog, clock.clone())
+                .unwrap(),
+        );
+        let xrl = Arc::new(ConcurrentCollection::with_clock(clock.clone()).with_max_limits(1));
+        StreamHandler::new(&rl, &xrl)
+    }
+
+    #[async_std::test]
+    async fn test_commands() {
+        let clock = ManualClock::new();
+        let mut handler = handler(&clock);
+
+        let increments = [
+            request(0x05, &delta(1), "foo"),
+            request(0x05, &delta(1), "foo"),
+            request(0x05, &delta(1), "foo"),
+        ]
+        .concat();
+        let mut stream = MockTcpStream::from_byte_chunks(&[&increments]);
+        handler.handle_one(&mut stream).await.unwrap();
+        assert_eq!(
+            responses(&stream.get_wbytes()),
+            vec![
+                (0x05, 0, "".to_string(), 0u64.to_be_bytes().to_vec()),
+                (0x05, 0, "".to_string(), 0u64.to_be_bytes().to_vec()),
+                (0x05, 0, "".to_string(), 1u64.to_be_bytes().to_vec()),
+            ]
+        );
+
+        let commands = [
+            request(0x00, &[], "foo"),
+            request(0x0c, &[], "bar"),
+            request(0x06, &delta(1), "foo"),
+            request(0x04, &[], "foo"),
+            request(0x04, &[], "foo"),
+            request(0x15, &delta(1), "foo"),
+            request(0x0a, &[], ""),
+            request(0x0b, &[], ""),
+        ]
+        .concat();
+        let mut stream = MockTcpStream::from_byte_chunks(&[&commands]);
+        handler.handle_one(&mut stream).await.unwrap();
+        assert_eq!(
+            responses(&stream.get_wbytes()),
+            vec![
+                (0x00, 0, "".to_string(), b"0".to_vec()),
+                (0x0c, 0, "bar".to_string(), b"2".to_vec()),
+                (0x06, 0, "".to_string(), 1u64.to_be_bytes().to_vec()),
+                (0x04, 0, "".to_string(), vec![]),
+                (0x04, 0x0001, "".to_string(), b"Not found".to_vec()),
+                (0x0a, 0, "".to_string(), vec![]),
+                (
+                    0x0b,
+                    0,
+                    "".to_string(),
+                    env!("CARGO_PKG_VERSION").as_bytes().to_vec()
+                ),
+            ]
+        );
+
+        // The quiet increment was recorded
+        let mut stream = MockTcpStream::from_byte_chunks(&[&request(0x00, &[], "foo")]);
+        handler.handle_one(&mut stream).await.unwrap();
+        assert_eq!(responses(&stream.get_wbytes())[0].3, b"1".to_vec());
+
+        // Quiet gets only skip misses
+        let quiet_gets = [request(0x0d, &[], "foo"), request(0x0a, &[], "")].concat();
+        let mut stream = MockTcpStream::from_byte_chunks(&[&quiet_gets]);
+        handler.handle_one(&mut stream).await.unwrap();
+        assert_eq!(
+            responses(&stream.get_wbytes()),
+            vec![
+                (0x0d, 0, "foo".to_string(), b"1".to_vec()),
+                (0x0a, 0, "".to_string(), vec![]),
+            ]
+        );
+
+        clock.advance(Duration::from_secs(1));
+        let mut stream = MockTcpStream::from_byte_chunks(&[&request(0x10, &[], "")]);
+        handler.handle_one(&mut stream).await.unwrap();
+        let stats = responses(&stream.get_wbytes());
+        assert!(stats.contains(&(0x10, 0, "cmd_incr".to_string(), b"4".to_vec())));
+        assert_eq!(stats.last(), Some(&(0x10, 0, "".to_string(), vec![])));
+    }
+
+    #[async_std::test]
+    async fn test_errors() {
+        let clock = ManualClock::new();
+        let mut handler = handler(&clock);
+
+        let requests = [
+            request(0x01, &[0; 8], "foo"),
+            request(0x05, &[], "foo"),
+            request(0x05, &delta(1), ""),
+            request(0x05, &delta(u64::from(MAX_COST) + 1), "foo"),
+            request(0x05, &delta(0), "foo"),
+            request(0x05, &delta(1), "0/1_foo"),
+            request(0x15, &delta(1), "1/1_foo"),
+            request(0x15, &delta(1), "2/1_foo"),
+            request(0x16, &delta(1), "foo"),
+        ]
+        .concat();
+        let mut stream = MockTcpStream::from_byte_chunks(&[&requests]);
+        handler.handle_one(&mut stream).await.unwrap();
+        let statuses: Vec<_> = responses(&stream.get_wbytes())
+            .iter()
+            .map(|(opcode, status, _, _)| (*opcode, *status))
+            .collect();
+        assert_eq!(
+            statuses,
+            vec![
+                (0x01, 0x0081),
+                (0x05, 0x0004),
+                (0x05, 0x0004),
+                (0x05, 0x0004),
+                (0x05, 0x0004),
+                (0x05, 0x0004),
+                (0x15, 0x0082),
+                (0x16, 0x0001)
+            ]
+        );
+
+        // A body too large is skipped, a request split across reads is handled once complete
+        let mut large = request(0x05, &delta(1), &"k".repeat(10_000));
+        large.extend_from_slice(&request(0x00, &[], "foo"));
+        let (first, second) = large.split_at(10);
+        let mut stream = MockTcpStream::from_byte_chunks(&[first, second]);
+        handler.handle_one(&mut stream).await.unwrap();
+        assert_eq!(stream.get_wbytes(), vec![]);
+        while handler.handle_one(&mut stream).await.is_ok() {}
+        let statuses: Vec<_> = responses(&stream.get_wbytes())
+            .iter()
+            .map(|(opcode, status, _, _)| (*opcode, *status))
+            .collect();
+        assert_eq!(statuses, vec![(0x05, 0x0003), (0x00, 0)]);
+    }
+
+    #[async_std::test]
+    async fn test_detection() {
+        let clock = ManualClock::new();
+
+        let requests = [
+            request(0x05, &delta(1), "foo"),
+            request(0x07, &[], ""),
+            request(0x05, &delta(1), "foo"),
+        ]
+        .concat();
+        let mut stream = MockTcpStream::from_byte_chunks(&[&requests]);
+        handler(&clock).main(&mut stream).await;
+        assert_eq!(
+            responses(&stream.get_wbytes()),
+            vec![
+                (0x05, 0, "".to_string(), 0u64.to_be_bytes().to_vec()),
+                (0x07, 0, "".to_string(), vec![]),
+            ]
+        );
+
+        let mut stream = MockTcpStream::from_chunks(&["incr foo\r\n"]);
+        handler(&clock).main(&mut stream).await;
+        assert_eq!(stream.get_wdata(), "0\r\n");
+
+        // Not a request, the connection is closed
+        let noop = request(0x0a, &[], "");
+        let mut stream = MockTcpStream::from_byte_chunks(&[&noop, &[0; HEADER_SIZE]]);
+        let mut handler = handler(&clock);
+        handler.handle_one(&mut stream).await.unwrap();
+        assert!(handler.handle_one(&mut stream).await.is_err());
+        assert_eq!(responses(&stream.get_wbytes()).len(), 1);
+    }
+}
@@ -1,13 +1,30 @@
+mod algorithm;
+mod clock;
 mod collection;
+mod composite;
+mod concurrent;
 mod config;
+mod eviction;
+mod expiry;
 mod handlers;
 mod ratelimit;
+mod rules;
+mod stats;
 
 #[cfg(test)]
 mod testing;
 
-pub use crate::collection::RatelimitCollection;
-pub use crate::ratelimit::{Ratelimit, RatelimitInvalidError};
+pub use crate::algorithm::Algorithm;
+pub use crate::clock::{Clock, ManualClock, SystemClock};
+pub use crate::collection::{
+    CollectionError, Limit, LimitRange, RatelimitCollection, SpecifiedKey,
+};
+pub use crate::composite::CompositeRatelimit;
+pub use crate::concurrent::{ConcurrentCollection, ConcurrentRatelimit};
+pub use crate::eviction::{Bounds, Eviction, FailMode};
+pub use crate::ratelimit::{Decision, Ratelimit, RatelimitInvalidError};
+pub use crate::rules::{KeyPattern, Rule, Rules};
+pub use crate::stats::Stats;
 
 pub use crate::config::Configuration;
 pub use crate::handlers::memcache::StreamHandler;
@@ -1,19 +1,37 @@
 use std::cmp;
-use std::time::Duration;
-
-#[cfg(not(test))]
-use std::time::Instant;
-
-#[cfg(test)]
-use mock_instant::Instant;
+use std::time::{Duration, Instant};
 
 use std::collections::HashMap;
 use std::convert::TryFrom;
+use std::sync::Arc;
+
+use crate::algorithm::{
+    Algorithm, FixedWindow, Gcra, SlidingWindow, Status, TokenBucket, WallClock,
+};
+use crate::clock::{Clock, SystemClock};
+use crate::eviction::{Bounds, Eviction, FailMode, Order, Rank};
+use crate::expiry::Expiries;
 
 const BLOCK_SIZE: usize = 64;
 
 /// 48 days, ~49 days being the number of milliseconds that fits in a u32
-const MAX_DURATION: u32 = 86400 * 48 * 1000;
+const MAX_MILLIS_DURATION: u64 = 86400 * 48 * 1000;
+
+/// 48000 days, the sliding log storing timestamps with a resolution of
+/// a second for durations greater than `MAX_MILLIS_DURATION`
+const MAX_DURATION: u64 = MAX_MILLIS_DURATION * 1000;
+
+/// Resolution of the timestamps stored by the sliding log, in milliseconds,
+/// so that `duration` always fits in a u32
+fn resolution(duration: u64) -> u64 {
+    if duration <= MAX_MILLIS_DURATION {
+        1
+    } else {
+        1000
+    }
+}
+
+/// Sliding log, timestamps are expressed in `resolution(duration)` units since the epoch
 struct RLEntry {
     epoch: Instant,
     index: u32,
@@ -21,20 +39,33 @@ struct RLEntry {
 }
 
 impl RLEntry {
-    fn new() -> RLEntry {
+    fn new(now: Instant, resolution: u64) -> RLEntry {
         RLEntry {
-            epoch: Instant::now() - Duration::from_millis(1),
+            epoch: now - Duration::from_millis(resolution),
             index: 0,
             timestamps: vec![0; BLOCK_SIZE],
         }
     }
 
-    /// `now` is the difference since epoch
-    fn rebase(&mut self, now: u32) -> u32 {
+    /// Number of `resolution` units elapsed between the epoch and `now`
+    fn elapsed(&self, now: Instant, resolution: u64) -> u64 {
+        u64::try_from(now.duration_since(self.epoch).as_millis()).unwrap() / resolution
+    }
+
+    /// `now` is the difference since epoch, `duration` is in `resolution` units.
+    /// Moves the epoch forward when `now` gets too large for a u32, expired timestamps
+    /// being cleared as the key may have been kept, or blocked, for long
+    fn rebase(&mut self, now: u64, resolution: u64, duration: u64) -> u32 {
         // let epoch = self.epoch;
 
-        if now < u32::MAX / 2 {
-            return now;
+        if now < u64::from(u32::MAX / 2) {
+            return u32::try_from(now).unwrap_or(u32::MAX);
+        }
+
+        for timestamp in self.timestamps.iter_mut() {
+            if now - u64::from(*timestamp) >= duration {
+                *timestamp = 0;
+            }
         }
         /*
         println!(
@@ -44,15 +75,15 @@ impl RLEntry {
         println!("Values: {:?}", self.timestamps);
          */
 
-        let min: u32 = match self.timestamps.iter().filter(|x| **x > 0).min() {
-            Some(x) => *x - 1,
-            None => 0,
+        let min: u64 = match self.timestamps.iter().filter(|x| **x > 0).min() {
+            Some(x) => u64::from(*x - 1),
+            None => now - 1,
         };
 
-        let new_epoch = self.epoch + Duration::from_millis(min.into());
+        let new_epoch = self.epoch + Duration::from_millis(min * resolution);
         for timestamp in self.timestamps.iter_mut() {
             if *timestamp > 0 {
-                *timestamp -= min;
+                *timestamp -= u32::try_from(min).unwrap_or(u32::MAX);
             }
         }
         self.epoch = new_epoch;
@@ -60,57 +91,341 @@ impl RLEntry {
         /* println!("new values: {:?}", self.timestamps);
         println!("min: {:?}, new={:?}", min, new_epoch); */
 
-        now - min
+        // The timestamps left are within `duration`, which fits in a u32
+        u32::try_from(now - min).unwrap_or(u32::MAX)
+    }
+
+    /// Whether `cost` hits would be allowed at `now`, without recording them
+    fn check(&self, now: Instant, size: u32, duration: u64, cost: u32) -> bool {
+        if cost > size {
+            return false;
+        }
+
+        let resolution = resolution(duration);
+        let now = self.elapsed(now, resolution);
+        let duration = duration.div_ceil(resolution);
+
+        // The `cost` slots following the index must all be free, as slots are sorted
+        // from the oldest to the newest only the last of them needs to be
+        match cost {
+            0 => true,
+            _ => match self.slot(usize::try_from(cost).unwrap() - 1, size) {
+                0 => true,
+                previous => now - u64::from(previous) >= duration,
+            },
+        }
     }
 
-    /// Hits the ratelimit
+    /// Hits the ratelimit `cost` times, either all hits are allowed or none
     /// size is the maximum number of hits allowed
     /// duration is the duration in milliseconds for which the hits are allowed
-    fn hit(&mut self, size: u32, duration: u32) -> bool {
-        let diff = Instant::now().duration_since(self.epoch);
-        let mut now = u32::try_from(diff.as_millis()).unwrap();
+    fn hit(&mut self, now: Instant, size: u32, duration: u64, cost: u32) -> bool {
+        if !self.check(now, size, duration, cost) {
+            return false;
+        }
+
+        let resolution = resolution(duration);
+        let now = self.elapsed(now, resolution);
 
         let index = usize::try_from(self.index).unwrap();
+        let max = usize::try_from(size).unwrap();
+        let cost = usize::try_from(cost).unwrap();
         {
-            if index == self.timestamps.len() {
-                let max = usize::try_from(size).unwrap();
-                let increment = cmp::min(BLOCK_SIZE, max - index);
+            let needed = cmp::min(max, index + cost);
+            if needed > self.timestamps.len() {
+                let increment = cmp::min(
+                    BLOCK_SIZE * (needed - self.timestamps.len()).div_ceil(BLOCK_SIZE),
+                    max - self.timestamps.len(),
+                );
                 self.timestamps.extend(vec![0; increment]);
             }
         }
 
-        now = self.rebase(now);
+        let now = self.rebase(now, resolution, duration.div_ceil(resolution));
 
-        let previous = self.timestamps[index];
-        //println!("ts: {:?}, now: {:?}, diff: {:?}", self.timestamps, now, diff);
+        //println!("ts: {:?}, now: {:?}", self.timestamps, now);
 
-        // println!("delta: {:?} index {:?}, previous {:?}, period: {:?}", delta, self.index, previous, period);
-        if previous > 0 && (now - previous) < duration {
-            return false;
-        } else {
-            self.timestamps[index] = now;
-            if self.index == size - 1 {
-                self.index = 0;
+        let end = cmp::min(max, index + cost);
+        self.timestamps[index..end].fill(now);
+        self.timestamps[..index + cost - end].fill(now);
+        self.index = u32::try_from((index + cost) % max).unwrap();
+        //println!("ts: {:?}", self.timestamps);
+
+        true
+    }
+
+    /// Timestamp stored `offset` slots after the index, 0 for unused slots
+    fn slot(&self, offset: usize, size: u32) -> u32 {
+        let index = usize::try_from(self.index).unwrap();
+        let max = usize::try_from(size).unwrap();
+
+        self.timestamps
+            .get((index + offset) % max)
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Status of the ratelimit at `now`, for hits of `cost` units
+    fn status(&self, now: Instant, size: u32, duration: u64, cost: u32) -> Status {
+        let resolution = resolution(duration);
+        let millis = self.elapsed(now, 1);
+        let now = millis / resolution;
+        let duration = duration.div_ceil(resolution);
+
+        let free = |timestamp: u32| timestamp == 0 || now - u64::from(timestamp) >= duration;
+        // The slot is freed when `now` reaches `timestamp + duration`
+        let wait = |timestamp: u32| {
+            let wait = ((u64::from(timestamp) + duration) * resolution).saturating_sub(millis);
+            Duration::from_millis(if timestamp == 0 { 0 } else { wait })
+        };
+
+        // Slots are sorted from the oldest to the newest starting at the index,
+        // so free slots are all at the beginning
+        let (mut low, mut high) = (0, usize::try_from(size).unwrap());
+        while low < high {
+            let middle = low + (high - low) / 2;
+            if free(self.slot(middle, size)) {
+                low = middle + 1;
             } else {
-                self.index += 1;
+                high = middle;
             }
         }
-        //println!("ts: {:?}", self.timestamps);
 
-        true
+        // The `cost` oldest slots must be freed
+        let cost = usize::try_from(cost).unwrap();
+        Status {
+            remaining: u32::try_from(low).unwrap(),
+            retry_after: if cost > usize::try_from(size).unwrap() {
+                Duration::MAX
+            } else if low >= cost {
+                Duration::ZERO
+            } else {
+                wait(self.slot(cost - 1, size))
+            },
+            reset_after: wait(self.slot(usize::try_from(size).unwrap() - 1, size)),
+        }
+    }
+
+    /// Cancels the `cost` most recent hits that did not expire yet
+    fn refund(&mut self, now: Instant, size: u32, duration: u64, cost: u32) {
+        let resolution = resolution(duration);
+        let now = self.elapsed(now, resolution);
+        let duration = duration.div_ceil(resolution);
+        let max = usize::try_from(size).unwrap();
+
+        for _ in 0..cost {
+            // The newest hit is right before the index
+            let last = self.slot(max - 1, size);
+            if last == 0 || now - u64::from(last) >= duration {
+                break;
+            }
+
+            let index = (usize::try_from(self.index).unwrap() + max - 1) % max;
+            self.timestamps[index] = 0;
+            self.index = u32::try_from(index).unwrap();
+        }
+    }
+
+    /// Instant of the last allowed hit
+    fn last_hit(&self, size: u32, resolution: u64) -> Instant {
+        let last = self.slot(usize::try_from(size).unwrap() - 1, size);
+
+        self.epoch + Duration::from_millis(u64::from(last) * resolution)
+    }
+}
+
+/// State of a single key, depending on the algorithm of the ratelimit
+enum Entry {
+    Log(Box<RLEntry>),
+    Bucket(TokenBucket),
+    Gcra(Gcra),
+    Window(SlidingWindow),
+    Fixed(FixedWindow),
+}
+
+impl Entry {
+    fn new(algorithm: Algorithm, duration: u64, now: Instant) -> Entry {
+        match algorithm {
+            Algorithm::SlidingLog => Entry::Log(Box::new(RLEntry::new(now, resolution(duration)))),
+            Algorithm::TokenBucket { burst } => {
+                Entry::Bucket(TokenBucket::new(duration, burst, now))
+            }
+            Algorithm::Gcra => Entry::Gcra(Gcra::new(now)),
+            Algorithm::SlidingWindow => Entry::Window(SlidingWindow::new(now)),
+            Algorithm::FixedWindow { .. } => Entry::Fixed(FixedWindow::new(now)),
+        }
+    }
+
+    fn hit(
+        &mut self,
+        now: Instant,
+        clock: &WallClock,
+        hits: u32,
+        duration: u64,
+        algorithm: Algorithm,
+        cost: u32,
+    ) -> bool {
+        match (self, algorithm) {
+            (Entry::Log(entry), _) => entry.hit(now, hits, duration, cost),
+            (Entry::Bucket(entry), Algorithm::TokenBucket { burst }) => {
+                entry.hit(now, hits, duration, burst, cost)
+            }
+            (Entry::Gcra(entry), _) => entry.hit(now, hits, duration, cost),
+            (Entry::Window(entry), _) => entry.hit(now, hits, duration, cost),
+            (Entry::Fixed(entry), Algorithm::FixedWindow { offset }) => {
+                entry.hit(now, clock, hits, duration, offset, cost)
+            }
+            _ => unreachable!("entry does not match the ratelimit algorithm"),
+        }
+    }
+
+    fn check(
+        &self,
+        now: Instant,
+        hits: u32,
+        duration: u64,
+        algorithm: Algorithm,
+        cost: u32,
+    ) -> bool {
+        match (self, algorithm) {
+            (Entry::Log(entry), _) => entry.check(now, hits, duration, cost),
+            (Entry::Bucket(entry), Algorithm::TokenBucket { burst }) => {
+                entry.check(now, hits, duration, burst, cost)
+            }
+            (Entry::Gcra(entry), _) => entry.check(now, hits, duration, cost),
+            (Entry::Window(entry), _) => entry.check(now, hits, duration, cost),
+            (Entry::Fixed(entry), _) => entry.check(now, hits, cost),
+            _ => unreachable!("entry does not match the ratelimit algorithm"),
+        }
+    }
+
+    fn refund(&mut self, now: Instant, hits: u32, duration: u64, algorithm: Algorithm, cost: u32) {
+        match (self, algorithm) {
+            (Entry::Log(entry), _) => entry.refund(now, hits, duration, cost),
+            (Entry::Bucket(entry), Algorithm::TokenBucket { burst }) => {
+                entry.refund(now, hits, duration, burst, cost)
+            }
+            (Entry::Gcra(entry), _) => entry.refund(now, hits, duration, cost),
+            (Entry::Window(entry), _) => entry.refund(now, duration, cost),
+            (Entry::Fixed(entry), _) => entry.refund(now, cost),
+            _ => unreachable!("entry does not match the ratelimit algorithm"),
+        }
+    }
+
+    fn status(
+        &self,
+        now: Instant,
+        hits: u32,
+        duration: u64,
+        algorithm: Algorithm,
+        cost: u32,
+    ) -> Status {
+        match (self, algorithm) {
+            (Entry::Log(entry), _) => entry.status(now, hits, duration, cost),
+            (Entry::Bucket(entry), Algorithm::TokenBucket { burst }) => {
+                entry.status(now, hits, duration, burst, cost)
+            }
+            (Entry::Gcra(entry), _) => entry.status(now, hits, duration, cost),
+            (Entry::Window(entry), _) => entry.status(now, hits, duration, cost),
+            (Entry::Fixed(entry), _) => entry.status(now, hits, cost),
+            _ => unreachable!("entry does not match the ratelimit algorithm"),
+        }
+    }
+
+    /// Instant after which the entry holds no more information
+    /// and can be safely removed
+    fn expires_at(&self, hits: u32, duration: u64, algorithm: Algorithm) -> Instant {
+        match (self, algorithm) {
+            (Entry::Log(entry), _) => {
+                entry.last_hit(hits, resolution(duration)) + Duration::from_millis(duration)
+            }
+            (Entry::Bucket(entry), Algorithm::TokenBucket { burst }) => {
+                entry.full_at(hits, duration, burst)
+            }
+            (Entry::Gcra(entry), _) => entry.tat(),
+            (Entry::Window(entry), _) => entry.expires_at(duration),
+            (Entry::Fixed(entry), _) => entry.ends(),
+            _ => unreachable!("entry does not match the ratelimit algorithm"),
+        }
+    }
+}
+
+/// Outcome of a hit, or of a check, with the state of the key,
+/// as needed for `X-RateLimit-*` or `Retry-After` headers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Decision {
+    /// Whether the hit is within the limits
+    pub allowed: bool,
+    /// Maximum number of hits allowed at once
+    pub limit: u32,
+    /// Number of hits still allowed right away
+    pub remaining: u32,
+    /// Time until a hit of the same cost is allowed, zero when it is, `Duration::MAX` when
+    /// it never will be as the cost exceeds the limit
+    pub retry_after: Duration,
+    /// Time until all hits are allowed again
+    pub reset_after: Duration,
+}
+
+impl Decision {
+    /// Decision of two limits stacked on the same key: allowed only if both are,
+    /// with the state of the most restrictive one
+    pub fn and(self, other: Decision) -> Decision {
+        Decision {
+            allowed: self.allowed && other.allowed,
+            limit: cmp::min(self.limit, other.limit),
+            remaining: cmp::min(self.remaining, other.remaining),
+            retry_after: cmp::max(self.retry_after, other.retry_after),
+            reset_after: cmp::max(self.reset_after, other.reset_after),
+        }
+    }
+}
+
+/// An entry along with its position in the eviction order, when keys are bounded,
+/// and the id of its push in the expiries
+struct Slot {
+    entry: Entry,
+    rank: Option<Rank>,
+    expiry: u64,
+    /// The key is limited until then, whatever its hits, see `Ratelimit::block`
+    blocked_until: Option<Instant>,
+}
+
+impl Slot {
+    fn blocked_at(&self, now: Instant) -> Option<Duration> {
+        self.blocked_until
+            .and_then(|until| until.checked_duration_since(now))
+            .filter(|blocked| !blocked.is_zero())
+    }
+
+    fn expires_at(&self, hits: u32, duration: u64, algorithm: Algorithm) -> Instant {
+        let expires_at = self.entry.expires_at(hits, duration, algorithm);
+        self.blocked_until
+            .map_or(expires_at, |until| cmp::max(expires_at, until))
     }
 }
 
-pub struct Ratelimit {
+pub struct Ratelimit<C: Clock = SystemClock> {
     hits: u32,
-    duration: u32,
-    entries: HashMap<String, RLEntry>,
+    duration: u64,
+    algorithm: Algorithm,
+    clock: C,
+    wall: WallClock,
+    /// Names are shared with the eviction order and the expiries
+    entries: HashMap<Arc<str>, Slot>,
+    expiries: Expiries,
+    bounds: Option<Bounds>,
+    /// Kept when keys are bounded, by this ratelimit or by its collection
+    order: Option<Order>,
+    evictions: u64,
+    /// Set by a full collection failing closed
+    pub(crate) refuse_new: bool,
 }
 
 #[derive(Debug, Clone)]
 pub struct RatelimitInvalidError {
     hits: u32,
-    duration: u32,
+    duration: u64,
 }
 
 impl std::error::Error for RatelimitInvalidError {}
@@ -127,18 +442,24 @@ impl std::fmt::Display for RatelimitInvalidError {
                 f,
                 "Invalid ratelimit specification, duration must be greater than 0"
             )
-        } else {
+        } else if self.duration > MAX_DURATION {
             write!(
                 f,
                 "Invalid ratelimit specification, duration must be less than {:?}",
                 MAX_DURATION
             )
+        } else {
+            write!(
+                f,
+                "Invalid ratelimit specification, burst must be between 1 and {:?}",
+                cmp::min(u64::from(u32::MAX), u64::MAX / self.duration)
+            )
         }
     }
 }
 
 impl Ratelimit {
-    pub fn check_bounds(hits: u32, duration: u32) -> Result<(), RatelimitInvalidError> {
+    pub fn check_bounds(hits: u32, duration: u64) -> Result<(), RatelimitInvalidError> {
         if hits == 0 || duration == 0 || duration > MAX_DURATION {
             Err(RatelimitInvalidError { hits, duration })
         } else {
@@ -146,27 +467,376 @@ impl Ratelimit {
         }
     }
 
-    pub fn new(hits: u32, duration: u32) -> Result<Ratelimit, RatelimitInvalidError> {
+    pub fn new(hits: u32, duration: u64) -> Result<Ratelimit, RatelimitInvalidError> {
+        Ratelimit::with_algorithm(hits, duration, Algorithm::SlidingLog)
+    }
+
+    pub fn with_algorithm(
+        hits: u32,
+        duration: u64,
+        algorithm: Algorithm,
+    ) -> Result<Ratelimit, RatelimitInvalidError> {
+        Ratelimit::with_clock(hits, duration, algorithm, SystemClock)
+    }
+}
+
+impl<C: Clock> Ratelimit<C> {
+    /// A ratelimit using the given source of time
+    pub fn with_clock(
+        hits: u32,
+        duration: u64,
+        algorithm: Algorithm,
+        clock: C,
+    ) -> Result<Ratelimit<C>, RatelimitInvalidError> {
         Ratelimit::check_bounds(hits, duration)?;
 
+        // The token bucket stores `burst * duration` units
+        if let Algorithm::TokenBucket { burst } = algorithm {
+            if burst == 0 || u64::from(burst).checked_mul(duration).is_none() {
+                return Err(RatelimitInvalidError { hits, duration });
+            }
+        }
+
         Ok(Ratelimit {
             hits,
             duration,
+            algorithm,
+            wall: WallClock::new(clock.now(), clock.unix()),
+            clock,
             entries: HashMap::new(),
+            expiries: Expiries::default(),
+            bounds: None,
+            order: None,
+            evictions: 0,
+            refuse_new: false,
         })
     }
 
+    /// Bounds the number of keys, evicting keys on new ones when full
+    pub fn with_bounds(mut self, bounds: Bounds) -> Ratelimit<C> {
+        self.set_bounds(bounds);
+        self
+    }
+
+    pub(crate) fn set_bounds(&mut self, bounds: Bounds) {
+        self.bounds = Some(bounds);
+        self.track(bounds.eviction);
+    }
+
+    /// Keeps the eviction order of the keys
+    pub(crate) fn track(&mut self, eviction: Eviction) {
+        let now = self.clock.now();
+        let mut order = Order::new(eviction);
+
+        for (name, slot) in self.entries.iter_mut() {
+            slot.rank = Some(order.insert(name, now));
+        }
+        self.order = Some(order);
+    }
+
+    pub fn algorithm(&self) -> Algorithm {
+        self.algorithm
+    }
+
+    /// Maximum number of hits allowed at once
+    pub fn limit(&self) -> u32 {
+        match self.algorithm {
+            Algorithm::TokenBucket { burst } => burst,
+            _ => self.hits,
+        }
+    }
+
+    pub(crate) fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
     pub fn hit(&mut self, name: &str) -> bool {
+        self.hit_n(name, 1)
+    }
+
+    /// Hits the ratelimit with a weight of `cost` units
+    /// Either all units fit within the limit, or none is consumed
+    pub fn hit_n(&mut self, name: &str, cost: u32) -> bool {
+        self.hit_at(name, cost, self.clock.now())
+    }
+
+    pub(crate) fn hit_at(&mut self, name: &str, cost: u32, now: Instant) -> bool {
+        let (hits, duration, algorithm) = (self.hits, self.duration, self.algorithm);
+
+        // Can never be allowed, refused before any state is created
+        if cost > self.limit() {
+            return false;
+        }
+
         match self.entries.get_mut(name) {
-            Some(entry) => entry.hit(self.hits, self.duration),
+            Some(slot) => {
+                if let (Some(order), Some(rank)) = (self.order.as_mut(), slot.rank) {
+                    slot.rank = Some(order.touch(rank, now));
+                }
+                slot.blocked_at(now).is_none()
+                    && slot
+                        .entry
+                        .hit(now, &self.wall, hits, duration, algorithm, cost)
+            }
+            None => {
+                let mut entry = Entry::new(algorithm, duration, now);
+                if !entry.hit(now, &self.wall, hits, duration, algorithm, cost)
+                    || !self.make_room(now)
+                {
+                    return false;
+                }
+
+                let name: Arc<str> = Arc::from(name);
+                let rank = self.order.as_mut().map(|order| order.insert(&name, now));
+                let expiry = self
+                    .expiries
+                    .push(&name, entry.expires_at(hits, duration, algorithm));
+                self.entries.insert(
+                    name,
+                    Slot {
+                        entry,
+                        rank,
+                        expiry,
+                        blocked_until: None,
+                    },
+                );
+
+                // Removed keys are only popped from the expiries once expired
+                if self.expiries.len() > 2 * self.entries.len() + BLOCK_SIZE {
+                    self.rebuild_expiries();
+                }
+                true
+            }
+        }
+    }
+
+    /// Whether a new key can be added, if needed by evicting another one
+    fn has_room(&self, now: Instant) -> bool {
+        if self.refuse_new {
+            return false;
+        }
+
+        match self.bounds {
+            None => true,
+            Some(bounds) if self.entries.len() < bounds.max_keys => true,
+            Some(Bounds {
+                fail: FailMode::Open,
+                max_keys,
+                ..
+            }) => max_keys > 0,
+            Some(_) => self.victim_expired(now) == Some(true),
+        }
+    }
+
+    /// Evicts keys until a new one can be added
+    fn make_room(&mut self, now: Instant) -> bool {
+        if !self.has_room(now) {
+            return false;
+        }
+
+        if let Some(bounds) = self.bounds {
+            while self.entries.len() >= bounds.max_keys {
+                self.evict();
+            }
+        }
+
+        true
+    }
+
+    /// Time until a new key may be added when failing closed: until all the hits of the next key
+    /// to be evicted expired, or the duration of the limit when this key is held by another limit
+    /// of the collection
+    fn room_after(&self, now: Instant) -> Duration {
+        let (hits, duration, algorithm) = (self.hits, self.duration, self.algorithm);
+        let victim = self.order.as_ref().and_then(Order::victim);
+
+        victim
+            .map(|(_, name)| self.entries[name].expires_at(hits, duration, algorithm))
+            .filter(|expires_at| *expires_at > now)
+            .map_or(Duration::from_millis(duration), |expires_at| {
+                expires_at.duration_since(now)
+            })
+    }
+
+    /// Whether all the hits of the next key to be evicted expired,
+    /// `None` when there is no such key
+    pub(crate) fn victim_expired(&self, now: Instant) -> Option<bool> {
+        let (hits, duration, algorithm) = (self.hits, self.duration, self.algorithm);
+        let (_, name) = self.order.as_ref()?.victim()?;
+
+        Some(self.entries[name].expires_at(hits, duration, algorithm) <= now)
+    }
+
+    /// Rank of the next key to be evicted
+    pub(crate) fn victim_rank(&self) -> Option<Rank> {
+        Some(self.order.as_ref()?.victim()?.0)
+    }
+
+    /// Evicts the next key to be evicted
+    pub(crate) fn evict(&mut self) {
+        if let Some(order) = self.order.as_mut() {
+            if let Some((rank, name)) = order.victim() {
+                self.entries.remove(name);
+                order.remove(rank);
+                self.evictions += 1;
+            }
+        }
+    }
+
+    /// Number of keys evicted to make room for new ones
+    pub fn evictions(&self) -> u64 {
+        self.evictions
+    }
+
+    /// Whether a hit would be allowed, without recording it
+    pub fn check(&self, name: &str) -> bool {
+        self.check_n(name, 1)
+    }
+
+    /// Whether a hit with a weight of `cost` units would be allowed, without recording it
+    pub fn check_n(&self, name: &str, cost: u32) -> bool {
+        self.check_at(name, cost, self.clock.now())
+    }
+
+    pub(crate) fn check_at(&self, name: &str, cost: u32, now: Instant) -> bool {
+        let (hits, duration, algorithm) = (self.hits, self.duration, self.algorithm);
+
+        if cost > self.limit() {
+            return false;
+        }
+
+        match self.entries.get(name) {
+            Some(slot) => {
+                slot.blocked_at(now).is_none()
+                    && slot.entry.check(now, hits, duration, algorithm, cost)
+            }
+            None => {
+                Entry::new(algorithm, duration, now).check(now, hits, duration, algorithm, cost)
+                    && self.has_room(now)
+            }
+        }
+    }
+
+    /// Same as `hit_n`, returning the decision along with the state of the key after the hit
+    pub fn hit_decision(&mut self, name: &str, cost: u32) -> Decision {
+        let now = self.clock.now();
+        let allowed = self.hit_at(name, cost, now);
+
+        self.decision(name, allowed, cost, now)
+    }
+
+    /// Same as `check_n`, returning the decision along with the state of the key
+    pub fn check_decision(&self, name: &str, cost: u32) -> Decision {
+        let now = self.clock.now();
+        let allowed = self.check_at(name, cost, now);
+
+        self.decision(name, allowed, cost, now)
+    }
+
+    pub(crate) fn decision(&self, name: &str, allowed: bool, cost: u32, now: Instant) -> Decision {
+        let (hits, duration, algorithm) = (self.hits, self.duration, self.algorithm);
+
+        let status = match self.entries.get(name) {
+            Some(slot) => {
+                let status = slot.entry.status(now, hits, duration, algorithm, cost);
+                match slot.blocked_at(now) {
+                    Some(blocked) => Status {
+                        remaining: 0,
+                        retry_after: cmp::max(status.retry_after, blocked),
+                        reset_after: cmp::max(status.reset_after, blocked),
+                    },
+                    None => status,
+                }
+            }
             None => {
-                let mut new_entry = RLEntry::new();
-                new_entry.hit(self.hits, self.duration);
-                self.entries.insert(name.to_string(), new_entry);
-                true // assumes that we are not limited to 0 hits
+                let status = Entry::new(algorithm, duration, now)
+                    .status(now, hits, duration, algorithm, cost);
+                match self.has_room(now) {
+                    true => status,
+                    false => Status {
+                        remaining: 0,
+                        retry_after: cmp::max(status.retry_after, self.room_after(now)),
+                        reset_after: status.reset_after,
+                    },
+                }
+            }
+        };
+
+        Decision {
+            allowed,
+            limit: self.limit(),
+            remaining: status.remaining,
+            retry_after: status.retry_after,
+            reset_after: status.reset_after,
+        }
+    }
+
+    /// Cancels `cost` hits of a key, the most recent ones, returns whether the key was known
+    pub fn refund(&mut self, name: &str, cost: u32) -> bool {
+        let now = self.clock.now();
+        let (hits, duration, algorithm) = (self.hits, self.duration, self.algorithm);
+
+        match self.entries.get_mut(name) {
+            Some(slot) => {
+                slot.entry.refund(now, hits, duration, algorithm, cost);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Limits a key for `duration`, whatever its hits, extending a previous block.
+    /// Blocks are at most as long as the longest limit, 48000 days.
+    /// Returns whether the key was known
+    pub fn block(&mut self, name: &str, duration: Duration) -> bool {
+        let now = self.clock.now();
+        let duration = cmp::min(duration, Duration::from_millis(MAX_DURATION));
+        let until = match now.checked_add(duration) {
+            Some(until) => until,
+            None => return self.entries.contains_key(name),
+        };
+
+        match self.entries.get_mut(name) {
+            Some(slot) => {
+                slot.blocked_until = Some(slot.blocked_until.map_or(until, |b| cmp::max(b, until)));
+                true
             }
+            None => false,
+        }
+    }
+
+    /// Undoes a hit of `cost` units recorded by a transaction failing afterwards,
+    /// removing the key if it was `added` by the hit
+    pub(crate) fn undo_hit(&mut self, name: &str, cost: u32, added: bool) {
+        if added {
+            self.remove(name);
+        } else {
+            self.refund(name, cost);
         }
     }
+
+    /// Whether the key is known
+    pub(crate) fn contains(&self, name: &str) -> bool {
+        self.entries.contains_key(name)
+    }
+
+    /// Clears the history of a key, returns whether the key was known
+    pub fn reset(&mut self, name: &str) -> bool {
+        self.remove(name)
+    }
+
+    fn remove(&mut self, name: &str) -> bool {
+        match self.entries.remove(name) {
+            Some(slot) => {
+                if let (Some(order), Some(rank)) = (self.order.as_mut(), slot.rank) {
+                    order.remove(rank);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
     pub fn len(&self) -> usize {
         self.entries.len()
     }
@@ -175,31 +845,71 @@ impl Ratelimit {
         self.entries.is_empty()
     }
 
+    /// Removes all the expired keys
     pub fn cleanup(&mut self) -> usize {
-        self.cleanup_at(Instant::now())
+        self.cleanup_at(self.clock.now())
     }
 
     /// Used by the collection mod so now is calculated outside a thread and can be properly mocked
     pub fn cleanup_at(&mut self, now: Instant) -> usize {
-        let min = now - Duration::from_millis(1000 + u64::from(self.duration));
-        let before = self.entries.len();
+        self.expire(now, &mut { usize::MAX })
+    }
 
-        self.entries.retain(|_, v| {
-            // println!("{:?}", v.timestamps);
-            let index = usize::try_from(v.index).unwrap();
+    /// Removes expired keys, looking at `batch` keys at most, so the ratelimit
+    /// is not blocked for long on large maps. See `has_expired`
+    pub fn cleanup_batch(&mut self, batch: usize) -> usize {
+        self.expire(self.clock.now(), &mut { batch })
+    }
+
+    /// Whether keys may still be removed by `cleanup_batch`
+    pub fn has_expired(&self) -> bool {
+        self.expiries.has_due(expired_before(self.clock.now()))
+    }
 
-            let last = match index {
-                0 => v.timestamps.len() - 1,
-                _ => index - 1,
+    /// Removes the keys expired at `now`, decrementing `budget` for each key looked at
+    pub(crate) fn expire(&mut self, now: Instant, budget: &mut usize) -> usize {
+        let min = expired_before(now);
+        let (hits, duration, algorithm) = (self.hits, self.duration, self.algorithm);
+        let mut removed = 0;
+
+        while *budget > 0 {
+            let (id, name) = match self.expiries.pop_due(min) {
+                Some(due) => due,
+                None => break,
             };
+            *budget -= 1;
 
-            let last_ts = v.epoch + Duration::from_millis(u64::from(v.timestamps[last]));
+            // Removed since, or removed and added again
+            let expires_at = match self.entries.get(&name) {
+                Some(slot) if slot.expiry == id => slot.expires_at(hits, duration, algorithm),
+                _ => continue,
+            };
 
-            last_ts > min
-        });
-        //self.entries.shrink_to_fit();
-        before - self.entries.len()
+            if expires_at > min {
+                self.expiries.push_again(id, name, expires_at);
+            } else {
+                self.remove(&name);
+                removed += 1;
+            }
+        }
+
+        removed
     }
+
+    fn rebuild_expiries(&mut self) {
+        let (hits, duration, algorithm) = (self.hits, self.duration, self.algorithm);
+
+        self.expiries.clear();
+        for (name, slot) in self.entries.iter_mut() {
+            let expires_at = slot.expires_at(hits, duration, algorithm);
+            slot.expiry = self.expiries.push(name, expires_at);
+        }
+    }
+}
+
+/// Keys are removed one second after their expiry
+fn expired_before(now: Instant) -> Instant {
+    now.checked_sub(Duration::from_secs(1)).unwrap_or(now)
 }
 
 #[cfg(test)]
@@ -207,7 +917,7 @@ mod test {
     #![allow(clippy::bool_assert_comparison)]
 
     use super::*;
-    use mock_instant::MockClock;
+    use crate::clock::ManualClock;
 
     /// Basic test "suite", hitting the rate limit within a
     /// specific time-frame will either return true or false
@@ -216,75 +926,173 @@ mod test {
         let ms1 = Duration::from_millis(1);
         let root = ms1;
         let rl_duration_ms = 2_000;
-        let rl_duration = Duration::from_millis(rl_duration_ms.into());
+        let rl_duration = Duration::from_millis(rl_duration_ms);
 
-        MockClock::set_time(root);
+        let clock = ManualClock::new();
+        clock.set_elapsed(root);
 
-        let mut rl = Ratelimit::new(10, rl_duration_ms).unwrap();
+        let mut rl =
+            Ratelimit::with_clock(10, rl_duration_ms, Algorithm::SlidingLog, clock.clone())
+                .unwrap();
         let st = "test";
 
         // 10 hits OK in 1 second
         for _ in 0..10 {
             assert_eq!(rl.hit(st), true);
 
-            MockClock::advance(Duration::from_millis(10));
+            clock.advance(Duration::from_millis(10));
         }
 
         // still not OK
         assert_eq!(rl.hit(st), false);
 
         // still not OK less than 2 seconds from the start
-        MockClock::set_time(root + rl_duration - ms1);
+        clock.set_elapsed(root + rl_duration - ms1);
         assert_eq!(rl.hit(st), false);
 
-        MockClock::advance(Duration::from_millis(1));
+        clock.advance(Duration::from_millis(1));
         assert_eq!(rl.hit(st), true);
     }
 
     #[test]
     fn test_overflow() {
-        MockClock::set_time(Duration::from_millis(200));
+        let clock = ManualClock::new();
 
-        let mut rl = Ratelimit::new(1, 86400 * 1000).unwrap();
+        let mut rl =
+            Ratelimit::with_clock(1, 86400 * 1000, Algorithm::SlidingLog, clock.clone()).unwrap();
         let st = "test";
 
-        MockClock::set_time(Duration::from_millis(200));
+        clock.set_elapsed(Duration::from_millis(200));
 
         for _i in 0..70 {
             assert!(rl.hit(st));
-            MockClock::advance(Duration::from_secs(86400));
+            clock.advance(Duration::from_secs(86400));
         }
     }
 
     #[test]
     fn test_cleanup() {
-        let root = Duration::from_millis(86_400_000);
         let rl_duration_ms = 60_000;
 
-        MockClock::set_time(root);
+        let clock = ManualClock::new();
 
-        let mut rl = Ratelimit::new(10, rl_duration_ms).unwrap();
+        let mut rl =
+            Ratelimit::with_clock(10, rl_duration_ms, Algorithm::SlidingLog, clock.clone())
+                .unwrap();
 
         rl.hit("foo");
         rl.hit("bar");
 
-        MockClock::advance(Duration::from_millis(59_000));
+        clock.advance(Duration::from_millis(59_000));
         rl.hit("bar");
 
         rl.cleanup();
         assert_eq!(rl.entries.len(), 2);
 
-        MockClock::advance(Duration::from_millis(3_000));
+        clock.advance(Duration::from_millis(3_000));
 
         rl.cleanup();
         assert_eq!(rl.entries.len(), 1);
 
-        MockClock::advance(Duration::from_millis(59_000));
+        clock.advance(Duration::from_millis(59_000));
 
         rl.cleanup();
         assert_eq!(rl.entries.len(), 0);
     }
 
+    #[test]
+    fn test_sliding_window() {
+        let clock = ManualClock::new();
+
+        let mut rl =
+            Ratelimit::with_clock(100, 60_000, Algorithm::SlidingWindow, clock.clone()).unwrap();
+
+        for _ in 0..100 {
+            assert!(rl.hit("foo"));
+        }
+        assert!(!rl.hit("foo"));
+
+        // Half of the previous window is still accounted for
+        clock.advance(Duration::from_millis(90_000));
+        for _ in 0..50 {
+            assert!(rl.hit("foo"));
+        }
+        assert!(!rl.hit("foo"));
+
+        clock.advance(Duration::from_millis(90_000));
+        assert_eq!(rl.cleanup(), 0);
+        clock.advance(Duration::from_millis(1_000));
+        assert_eq!(rl.cleanup(), 1);
+    }
+
+    #[test]
+    fn test_fixed_window() {
+        // 2022-10-01 12:20:00 UTC
+        let clock = ManualClock::new().with_unix(Duration::from_secs(1_664_626_800));
+
+        // Hourly quota reset at 30 minutes past the hour
+        let algorithm = Algorithm::FixedWindow { offset: 1_800_000 };
+        let mut rl = Ratelimit::with_clock(2, 3_600_000, algorithm, clock.clone()).unwrap();
+
+        assert!(rl.hit("foo"));
+        assert!(rl.hit("foo"));
+        assert!(!rl.hit("foo"));
+
+        // 12:30:00, expired at the window edge
+        clock.advance(Duration::from_secs(600));
+        assert_eq!(rl.cleanup(), 0);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(rl.cleanup(), 1);
+
+        assert!(rl.hit("foo"));
+        assert!(rl.hit("foo"));
+        assert!(!rl.hit("foo"));
+    }
+
+    #[test]
+    fn test_long_duration() {
+        let clock = ManualClock::new();
+
+        // 90 days, stored with a resolution of a second
+        let duration = 90 * 86_400_000;
+        let mut rl =
+            Ratelimit::with_clock(3, duration, Algorithm::SlidingLog, clock.clone()).unwrap();
+
+        assert!(rl.hit("foo"));
+        clock.advance(Duration::from_secs(50 * 86_400));
+        assert!(rl.hit_n("foo", 2));
+        assert!(!rl.hit("foo"));
+
+        // the first hit is freed after 90 days
+        clock.advance(Duration::from_secs(40 * 86_400 - 1));
+        assert!(!rl.hit("foo"));
+        assert_eq!(
+            rl.check_decision("foo", 1).retry_after,
+            Duration::from_secs(1)
+        );
+        clock.advance(Duration::from_secs(1));
+        assert!(rl.hit("foo"));
+        assert!(!rl.hit("foo"));
+
+        assert_eq!(rl.cleanup(), 0);
+        clock.advance(Duration::from_secs(90 * 86_400 + 1));
+        assert_eq!(rl.cleanup(), 1);
+
+        for algorithm in [
+            Algorithm::TokenBucket { burst: 3 },
+            Algorithm::Gcra,
+            Algorithm::SlidingWindow,
+        ] {
+            let mut rl = Ratelimit::with_clock(3, duration, algorithm, clock.clone()).unwrap();
+
+            assert!(rl.hit_n("foo", 3));
+            assert!(!rl.hit("foo"));
+
+            clock.advance(Duration::from_secs(2 * 90 * 86_400));
+            assert!(rl.hit_n("foo", 3));
+        }
+    }
+
     #[test]
     fn test_bounds() {
         let fail = Ratelimit::new(0, 10);
@@ -292,7 +1100,399 @@ mod test {
         let fail = Ratelimit::new(10, 0);
         assert!(fail.is_err());
 
-        let fail = Ratelimit::new(10, u32::MAX - 42);
+        let fail = Ratelimit::new(10, MAX_DURATION + 1);
+        assert!(fail.is_err());
+
+        let fail = Ratelimit::with_algorithm(10, 10, Algorithm::TokenBucket { burst: 0 });
+        assert!(fail.is_err());
+        let algorithm = Algorithm::TokenBucket { burst: u32::MAX };
+        let fail = Ratelimit::with_algorithm(10, MAX_DURATION, algorithm);
         assert!(fail.is_err());
     }
+
+    #[test]
+    fn test_hit_n() {
+        let clock = ManualClock::new();
+
+        let mut rl =
+            Ratelimit::with_clock(100, 1_000, Algorithm::SlidingLog, clock.clone()).unwrap();
+
+        // More than the limit is never allowed, and not recorded
+        assert!(!rl.hit_n("foo", 101));
+        assert!(rl.is_empty());
+
+        assert!(rl.hit_n("foo", 60));
+        clock.advance(Duration::from_millis(500));
+        assert!(rl.hit_n("foo", 30));
+
+        // All or nothing
+        assert!(!rl.hit_n("foo", 11));
+        assert!(rl.hit_n("foo", 10));
+        assert!(!rl.hit("foo"));
+
+        // The first 60 are expired, across the end of the ring
+        clock.advance(Duration::from_millis(500));
+        assert!(!rl.hit_n("foo", 61));
+        assert!(rl.hit_n("foo", 60));
+        assert!(!rl.hit("foo"));
+
+        // Only the last slot needed is looked at
+        let mut rl =
+            Ratelimit::with_clock(u32::MAX, 1_000, Algorithm::SlidingLog, clock.clone()).unwrap();
+        assert!(rl.hit_n("foo", 2));
+        assert!(!rl.check_n("foo", u32::MAX));
+        assert!(rl.check_n("foo", u32::MAX - 2));
+    }
+
+    #[test]
+    fn test_check() {
+        let clock = ManualClock::new();
+
+        for algorithm in [
+            Algorithm::SlidingLog,
+            Algorithm::TokenBucket { burst: 2 },
+            Algorithm::Gcra,
+            Algorithm::SlidingWindow,
+            Algorithm::FixedWindow { offset: 0 },
+        ] {
+            let mut rl = Ratelimit::with_clock(2, 3_600_000, algorithm, clock.clone()).unwrap();
+
+            // Checks are not recorded
+            for _ in 0..10 {
+                assert!(rl.check("foo"));
+            }
+            assert!(rl.is_empty());
+            assert!(!rl.check_n("foo", 3));
+
+            assert!(rl.hit("foo"));
+            assert!(rl.check("foo"));
+            assert!(!rl.check_n("foo", 2));
+            assert!(rl.hit("foo"));
+            assert!(!rl.check("foo"));
+            assert!(!rl.hit("foo"));
+        }
+    }
+
+    #[test]
+    fn test_decision() {
+        let clock = ManualClock::new();
+
+        let mut rl =
+            Ratelimit::with_clock(3, 10_000, Algorithm::SlidingLog, clock.clone()).unwrap();
+
+        assert_eq!(
+            rl.check_decision("foo", 1),
+            Decision {
+                allowed: true,
+                limit: 3,
+                remaining: 3,
+                retry_after: Duration::ZERO,
+                reset_after: Duration::ZERO,
+            }
+        );
+
+        rl.hit("foo");
+        clock.advance(Duration::from_millis(1_000));
+        rl.hit("foo");
+        clock.advance(Duration::from_millis(1_000));
+
+        assert_eq!(
+            rl.hit_decision("foo", 1),
+            Decision {
+                allowed: true,
+                limit: 3,
+                remaining: 0,
+                retry_after: Duration::from_millis(8_000),
+                reset_after: Duration::from_millis(10_000),
+            }
+        );
+        assert!(!rl.hit_decision("foo", 1).allowed);
+
+        // The first hit expired, two hits are allowed once the second one expires
+        clock.advance(Duration::from_millis(8_000));
+        assert_eq!(
+            rl.check_decision("foo", 2),
+            Decision {
+                allowed: false,
+                limit: 3,
+                remaining: 1,
+                retry_after: Duration::from_millis(1_000),
+                reset_after: Duration::from_millis(2_000),
+            }
+        );
+    }
+
+    #[test]
+    fn test_decision_large() {
+        let clock = ManualClock::new();
+
+        // Partially filled ring
+        let mut rl =
+            Ratelimit::with_clock(1_000, 10_000, Algorithm::SlidingLog, clock.clone()).unwrap();
+        assert!(rl.hit_n("foo", 100));
+        clock.advance(Duration::from_millis(5_000));
+        assert!(rl.hit_n("foo", 850));
+
+        let decision = rl.check_decision("foo", 1);
+        assert_eq!(decision.remaining, 50);
+        assert_eq!(decision.reset_after, Duration::from_millis(10_000));
+
+        assert!(rl.hit_n("foo", 50));
+        let decision = rl.check_decision("foo", 1);
+        assert_eq!(decision.remaining, 0);
+        assert_eq!(decision.retry_after, Duration::from_millis(5_000));
+
+        clock.advance(Duration::from_millis(5_000));
+        assert_eq!(rl.check_decision("foo", 1).remaining, 100);
+    }
+
+    #[test]
+    fn test_hit_n_algorithms() {
+        let clock = ManualClock::new();
+
+        for algorithm in [
+            Algorithm::TokenBucket { burst: 10 },
+            Algorithm::Gcra,
+            Algorithm::SlidingWindow,
+            Algorithm::FixedWindow { offset: 0 },
+        ] {
+            let mut rl = Ratelimit::with_clock(10, 3_600_000, algorithm, clock.clone()).unwrap();
+
+            assert!(!rl.hit_n("foo", 11));
+            assert!(rl.hit_n("foo", 7));
+            assert!(!rl.hit_n("foo", 4));
+            assert!(rl.hit_n("foo", 3));
+            assert!(!rl.hit("foo"));
+        }
+    }
+
+    #[test]
+    fn test_reset() {
+        let clock = ManualClock::new();
+
+        let mut rl =
+            Ratelimit::with_clock(1, 60_000, Algorithm::SlidingLog, clock.clone()).unwrap();
+
+        assert!(rl.hit("foo"));
+        assert!(!rl.hit("foo"));
+        assert!(rl.hit("bar"));
+
+        assert!(rl.reset("foo"));
+        assert!(!rl.reset("foo"));
+        assert_eq!(rl.len(), 1);
+
+        assert!(rl.hit("foo"));
+        assert!(!rl.hit("bar"));
+    }
+
+    #[test]
+    fn test_refund() {
+        let clock = ManualClock::new();
+
+        for algorithm in [
+            Algorithm::SlidingLog,
+            Algorithm::TokenBucket { burst: 3 },
+            Algorithm::Gcra,
+            Algorithm::SlidingWindow,
+            Algorithm::FixedWindow { offset: 0 },
+        ] {
+            let mut rl = Ratelimit::with_clock(3, 60_000, algorithm, clock.clone()).unwrap();
+
+            assert!(!rl.refund("foo", 1), "{:?}", algorithm);
+            assert!(rl.hit_n("foo", 3), "{:?}", algorithm);
+            assert!(!rl.hit("foo"), "{:?}", algorithm);
+
+            assert!(rl.refund("foo", 2), "{:?}", algorithm);
+            assert!(rl.hit_n("foo", 2), "{:?}", algorithm);
+            assert!(!rl.hit("foo"), "{:?}", algorithm);
+
+            // Never more than the hits recorded
+            assert!(rl.refund("foo", 10), "{:?}", algorithm);
+            assert!(rl.hit_n("foo", 3), "{:?}", algorithm);
+            assert!(!rl.hit("foo"), "{:?}", algorithm);
+        }
+    }
+
+    #[test]
+    fn test_block() {
+        let clock = ManualClock::new();
+
+        let mut rl =
+            Ratelimit::with_clock(10, 1_000, Algorithm::SlidingLog, clock.clone()).unwrap();
+
+        assert!(!rl.block("foo", Duration::from_secs(30)));
+        assert!(rl.hit("foo"));
+        assert!(rl.block("foo", Duration::from_secs(30)));
+        // A shorter block does not shorten it
+        assert!(rl.block("foo", Duration::from_secs(10)));
+
+        let decision = rl.check_decision("foo", 1);
+        assert!(!decision.allowed);
+        assert_eq!(decision.remaining, 0);
+        assert_eq!(decision.retry_after, Duration::from_secs(30));
+
+        // Kept until the end of the block
+        clock.advance(Duration::from_secs(29));
+        assert_eq!(rl.cleanup(), 0);
+        assert!(!rl.hit("foo"));
+
+        clock.advance(Duration::from_secs(1));
+        assert!(rl.hit("foo"));
+        assert!(rl.hit("bar"));
+
+        // Blocks are bounded, and the log is rebased after blocks longer than its u32 range
+        assert!(rl.block("bar", Duration::from_secs(u64::MAX)));
+        assert!(rl.block("foo", Duration::from_secs(60 * 86400)));
+        clock.advance(Duration::from_secs(60 * 86400));
+        assert!(!rl.hit("bar"));
+        for _ in 0..10 {
+            assert!(rl.hit("foo"));
+        }
+        assert!(!rl.hit("foo"));
+        clock.advance(Duration::from_millis(1_000));
+        assert!(rl.hit("foo"));
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let clock = ManualClock::new();
+
+        // 1 hit per second on average, bursts of 3
+        let algorithm = Algorithm::TokenBucket { burst: 3 };
+        let mut rl = Ratelimit::with_clock(1, 1_000, algorithm, clock.clone()).unwrap();
+
+        for _ in 0..3 {
+            assert!(rl.hit("foo"));
+        }
+        assert!(!rl.hit("foo"));
+
+        clock.advance(Duration::from_millis(1_000));
+        assert!(rl.hit("foo"));
+        assert!(!rl.hit("foo"));
+
+        // Full again after 3 seconds, + 1 second of grace
+        clock.advance(Duration::from_millis(3_999));
+        assert_eq!(rl.cleanup(), 0);
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(rl.cleanup(), 1);
+    }
+
+    #[test]
+    fn test_gcra() {
+        let clock = ManualClock::new();
+
+        let mut rl = Ratelimit::with_clock(4, 2_000, Algorithm::Gcra, clock.clone()).unwrap();
+
+        for _ in 0..4 {
+            assert!(rl.hit("foo"));
+        }
+        assert!(!rl.hit("foo"));
+
+        // a hit every 500ms
+        clock.advance(Duration::from_millis(500));
+        assert!(rl.hit("foo"));
+        assert!(!rl.hit("foo"));
+
+        clock.advance(Duration::from_millis(2_999));
+        assert_eq!(rl.cleanup(), 0);
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(rl.cleanup(), 1);
+    }
+
+    #[test]
+    fn test_bounds_eviction() {
+        let clock = ManualClock::new();
+        let bounds = Bounds::new(2);
+
+        let mut rl = Ratelimit::with_clock(1, 60_000, Algorithm::SlidingLog, clock.clone())
+            .unwrap()
+            .with_bounds(bounds);
+
+        assert!(rl.hit("foo"));
+        assert!(rl.hit("bar"));
+        assert!(!rl.hit("foo"));
+
+        // bar is the least recently used, evicted and allowed again
+        assert!(rl.hit("baz"));
+        assert_eq!(rl.len(), 2);
+        assert_eq!(rl.evictions(), 1);
+        assert!(!rl.check("foo"));
+        assert!(rl.hit("bar"));
+        assert_eq!(rl.evictions(), 2);
+
+        // foo is the least frequently used
+        let mut rl = Ratelimit::with_clock(1, 60_000, Algorithm::SlidingLog, clock.clone())
+            .unwrap()
+            .with_bounds(bounds.with_eviction(Eviction::Lfu));
+
+        assert!(rl.hit("foo"));
+        assert!(rl.hit("bar"));
+        assert!(!rl.hit("bar"));
+        assert!(rl.hit("baz"));
+        assert!(!rl.hit("bar"));
+        assert!(rl.hit("foo"));
+    }
+
+    #[test]
+    fn test_bounds_fail_closed() {
+        let clock = ManualClock::new();
+        let bounds = Bounds::new(2).with_fail(FailMode::Closed);
+
+        let mut rl = Ratelimit::with_clock(1, 60_000, Algorithm::SlidingLog, clock.clone())
+            .unwrap()
+            .with_bounds(bounds);
+
+        assert!(rl.hit("foo"));
+        clock.advance(Duration::from_secs(1));
+        assert!(rl.hit("bar"));
+
+        // No key can be evicted without losing its hits
+        assert!(!rl.check("baz"));
+        assert!(!rl.hit("baz"));
+        assert_eq!(rl.evictions(), 0);
+
+        // Until the hits of foo expire
+        clock.advance(Duration::from_secs(59));
+        assert!(rl.check("baz"));
+        assert!(rl.hit("baz"));
+        assert_eq!(rl.evictions(), 1);
+        assert!(!rl.hit("bar"));
+
+        assert!(rl.reset("bar"));
+        assert!(rl.hit("foo"));
+        assert_eq!(rl.evictions(), 1);
+    }
+
+    #[test]
+    fn test_cleanup_batch() {
+        let clock = ManualClock::new();
+        let mut rl =
+            Ratelimit::with_clock(2, 10_000, Algorithm::SlidingLog, clock.clone()).unwrap();
+
+        for i in 0..10 {
+            rl.hit(&format!("key{}", i));
+        }
+        clock.advance(Duration::from_secs(5));
+        // Hit since it was added, its expiry is pushed back
+        rl.hit("key0");
+        // Removed then added again, its first expiry is outdated
+        rl.reset("key1");
+        rl.hit("key1");
+
+        assert!(!rl.has_expired());
+        clock.advance(Duration::from_secs(6));
+        assert!(rl.has_expired());
+
+        // key0 and key1 are looked at, but not removed
+        assert_eq!(rl.cleanup_batch(4), 2);
+        assert!(rl.has_expired());
+        assert_eq!(rl.cleanup_batch(4), 4);
+        assert_eq!(rl.cleanup_batch(4), 2);
+        assert!(!rl.has_expired());
+        assert_eq!(rl.len(), 2);
+
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(rl.cleanup_batch(4), 2);
+        assert!(rl.is_empty());
+    }
 }
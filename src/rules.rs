@@ -0,0 +1,177 @@
+use regex::Regex;
+
+use crate::clock::{Clock, SystemClock};
+use crate::concurrent::ConcurrentRatelimit;
+
+/// How a rule matches keys
+#[derive(Debug, Clone)]
+pub enum KeyPattern {
+    /// Keys starting with the prefix
+    Prefix(String),
+    /// Keys matching the regular expression, anywhere unless anchored
+    Regex(Regex),
+}
+
+impl KeyPattern {
+    pub fn matches(&self, keyname: &str) -> bool {
+        match self {
+            KeyPattern::Prefix(prefix) => keyname.starts_with(prefix.as_str()),
+            KeyPattern::Regex(regex) => regex.is_match(keyname),
+        }
+    }
+}
+
+/// Named limits, applied to the keys matching its pattern
+///
+/// The limits are stacked, such as a burst and a sustained limit:
+/// a hit is only recorded if all of them allow it
+pub struct Rule<C: Clock = SystemClock> {
+    name: String,
+    pattern: KeyPattern,
+    limits: Vec<ConcurrentRatelimit<C>>,
+}
+
+impl<C: Clock> Rule<C> {
+    pub fn new(name: &str, pattern: KeyPattern, limits: Vec<ConcurrentRatelimit<C>>) -> Rule<C> {
+        Rule {
+            name: name.to_string(),
+            pattern,
+            limits,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn pattern(&self) -> &KeyPattern {
+        &self.pattern
+    }
+
+    /// The stacked limits, always locked in this order
+    pub fn limits(&self) -> &[ConcurrentRatelimit<C>] {
+        &self.limits
+    }
+
+    pub fn matches(&self, keyname: &str) -> bool {
+        self.pattern.matches(keyname)
+    }
+}
+
+/// Rules in order of priority, a key is limited by the first rule it matches
+pub struct Rules<C: Clock = SystemClock> {
+    rules: Vec<Rule<C>>,
+}
+
+impl<C: Clock> Default for Rules<C> {
+    fn default() -> Rules<C> {
+        Rules { rules: vec![] }
+    }
+}
+
+impl Rules {
+    pub fn new() -> Rules {
+        Rules::default()
+    }
+}
+
+impl<C: Clock> Rules<C> {
+    /// Adds a rule, with a lower priority than the previous ones
+    pub fn with_rule(mut self, rule: Rule<C>) -> Rules<C> {
+        self.rules.push(rule);
+        self
+    }
+
+    /// Index of the first rule matching the key
+    pub fn position(&self, keyname: &str) -> Option<usize> {
+        self.rules.iter().position(|rule| rule.matches(keyname))
+    }
+
+    /// The first rule matching the key
+    pub fn find(&self, keyname: &str) -> Option<&Rule<C>> {
+        self.position(keyname).map(|index| &self.rules[index])
+    }
+
+    pub fn get(&self, index: usize) -> Option<&Rule<C>> {
+        self.rules.get(index)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Rule<C>> {
+        self.rules.iter()
+    }
+
+    /// Number of rules
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Removes expired keys of all the rules, see `ConcurrentRatelimit::cleanup_batch`
+    pub fn cleanup_batch(&self, batch: usize) -> usize {
+        self.rules
+            .iter()
+            .flat_map(|rule| rule.limits.iter())
+            .map(|rl| rl.cleanup_batch(batch))
+            .sum()
+    }
+
+    pub fn has_expired(&self) -> bool {
+        self.rules
+            .iter()
+            .flat_map(|rule| rule.limits.iter())
+            .any(|rl| rl.has_expired())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Algorithm;
+    use crate::ManualClock;
+    use std::time::Duration;
+
+    #[test]
+    fn test_rules() {
+        let clock = ManualClock::new();
+        let ratelimit = |hits| {
+            ConcurrentRatelimit::with_clock(hits, 1_000, Algorithm::SlidingLog, clock.clone())
+                .unwrap()
+        };
+
+        let rules = Rules::default()
+            .with_rule(Rule::new(
+                "login",
+                KeyPattern::Regex(Regex::new("^login:").unwrap()),
+                vec![ratelimit(1)],
+            ))
+            .with_rule(Rule::new(
+                "api",
+                KeyPattern::Prefix("api:".to_string()),
+                vec![ratelimit(2)],
+            ))
+            .with_rule(Rule::new(
+                "any-login",
+                KeyPattern::Regex(Regex::new("login").unwrap()),
+                vec![ratelimit(3)],
+            ));
+
+        assert_eq!(rules.len(), 3);
+        assert_eq!(rules.find("login:foo").map(Rule::name), Some("login"));
+        assert_eq!(rules.find("api:login:foo").map(Rule::name), Some("api"));
+        assert_eq!(rules.find("foo:login").map(Rule::name), Some("any-login"));
+        assert_eq!(rules.position("api:foo"), Some(1));
+        assert!(rules.find("foo:api:").is_none());
+
+        let login = rules.find("login:foo").unwrap();
+        assert!(login.limits()[0].hit("login:foo"));
+        assert!(!login.limits()[0].hit("login:foo"));
+        assert!(!rules.has_expired());
+
+        clock.advance(Duration::from_secs(3));
+        assert!(rules.has_expired());
+        assert_eq!(rules.cleanup_batch(10), 1);
+    }
+}
@@ -0,0 +1,45 @@
+use std::sync::atomic::AtomicU64;
+use std::time::{Duration, Instant};
+
+/// Counters of a server, shared by its connections
+pub struct Stats {
+    started: Instant,
+    pub(crate) curr_connections: AtomicU64,
+    pub(crate) total_connections: AtomicU64,
+    pub(crate) cmd_get: AtomicU64,
+    pub(crate) cmd_incr: AtomicU64,
+    pub(crate) cmd_decr: AtomicU64,
+    pub(crate) cmd_delete: AtomicU64,
+    pub(crate) cmd_touch: AtomicU64,
+    /// Hits allowed by `incr` and `incr_all`
+    pub(crate) hits_allowed: AtomicU64,
+    /// Hits refused by `incr` and `incr_all`
+    pub(crate) hits_limited: AtomicU64,
+}
+
+impl Default for Stats {
+    fn default() -> Stats {
+        Stats {
+            started: Instant::now(),
+            curr_connections: AtomicU64::new(0),
+            total_connections: AtomicU64::new(0),
+            cmd_get: AtomicU64::new(0),
+            cmd_incr: AtomicU64::new(0),
+            cmd_decr: AtomicU64::new(0),
+            cmd_delete: AtomicU64::new(0),
+            cmd_touch: AtomicU64::new(0),
+            hits_allowed: AtomicU64::new(0),
+            hits_limited: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Stats {
+    pub fn new() -> Stats {
+        Stats::default()
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+}
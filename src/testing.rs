@@ -1,36 +1,78 @@
 #[derive(Default)]
 pub struct MockTcpStream {
     read_data: Vec<u8>,
+    /// Returned by the successive reads instead of `read_data` when set
+    chunks: Option<VecDeque<Vec<u8>>>,
     write_data: Vec<u8>,
+    /// Maximum number of bytes accepted by each write, when set
+    max_write: Option<usize>,
 }
 use async_std::io::{Read, Write};
 use futures::io::Error;
 use futures::task::{Context, Poll};
 use std::cmp::min;
+use std::collections::VecDeque;
 use std::pin::Pin;
 
 impl MockTcpStream {
     pub fn from_rdata(data: String) -> MockTcpStream {
         MockTcpStream {
             read_data: data.as_bytes().into(),
+            chunks: None,
             write_data: vec![],
+            max_write: None,
         }
     }
 
-    // Returns the data that was written, as string, clearing it
+    /// A stream returning each chunk by a single read, then the end of the stream
+    pub fn from_chunks(chunks: &[&str]) -> MockTcpStream {
+        let chunks: Vec<&[u8]> = chunks.iter().map(|x| x.as_bytes()).collect();
+        MockTcpStream::from_byte_chunks(&chunks)
+    }
+
+    /// Same as `from_chunks`, for binary data
+    pub fn from_byte_chunks(chunks: &[&[u8]]) -> MockTcpStream {
+        MockTcpStream {
+            read_data: vec![],
+            chunks: Some(chunks.iter().map(|x| x.to_vec()).collect()),
+            write_data: vec![],
+            max_write: None,
+        }
+    }
+
+    /// Accepts at most `size` bytes by write, as a socket with a full buffer
+    pub fn with_max_write(mut self, size: usize) -> MockTcpStream {
+        self.max_write = Some(size);
+        self
+    }
+
+    // Returns the data written since the last call, as string, clearing it
     pub fn get_wdata(&mut self) -> String {
-        let ret = std::str::from_utf8(&self.write_data).unwrap().to_string();
-        self.write_data.clear();
-        ret
+        std::str::from_utf8(&self.get_wbytes()).unwrap().to_string()
+    }
+
+    // Returns the data written since the last call, clearing it
+    pub fn get_wbytes(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.write_data)
     }
 }
 
 impl Read for MockTcpStream {
     fn poll_read(
-        self: Pin<&mut Self>,
+        mut self: Pin<&mut Self>,
         _: &mut Context,
         buf: &mut [u8],
     ) -> Poll<Result<usize, Error>> {
+        if let Some(chunks) = self.chunks.as_mut() {
+            let chunk = chunks.pop_front().unwrap_or_default();
+            let size: usize = min(chunk.len(), buf.len());
+            buf[..size].copy_from_slice(&chunk[..size]);
+            if size < chunk.len() {
+                chunks.push_front(chunk[size..].to_vec());
+            }
+            return Poll::Ready(Ok(size));
+        }
+
         let size: usize = min(self.read_data.len(), buf.len());
         buf[..size].copy_from_slice(&self.read_data[..size]);
         Poll::Ready(Ok(size))
@@ -43,9 +85,10 @@ impl Write for MockTcpStream {
         _: &mut Context,
         buf: &[u8],
     ) -> Poll<Result<usize, Error>> {
-        self.write_data = Vec::from(buf);
+        let size = self.max_write.map_or(buf.len(), |max| min(max, buf.len()));
+        self.write_data.extend_from_slice(&buf[..size]);
 
-        Poll::Ready(Ok(buf.len()))
+        Poll::Ready(Ok(size))
     }
 
     fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<Result<(), Error>> {